@@ -0,0 +1,325 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::{sync::mpsc, time::Instant};
+use web3::{types::U64, Transport};
+
+use crate::{
+    plan_scan, shutdown::Shutdown, EoEvent, EoServer, EoServerError, RegisteredEvent, ScanPlan,
+};
+
+/// How many chunks are fetched at the same time by default
+pub const DEFAULT_BACKFILL_CONCURRENCY: usize = 4;
+
+/// How the history up to the head is caught up on before live tailing
+/// starts.
+///
+/// The range is split into chunks of the scheduler's chunk size, `concurrency`
+/// of which are fetched at once. Their logs are processed and delivered in
+/// chain order, whatever order the calls finish in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackfillConfig {
+    pub concurrency: usize,
+    /// The most `eth_getLogs` calls started per second, `None` for no limit
+    pub requests_per_second: Option<u32>,
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        BackfillConfig {
+            concurrency: DEFAULT_BACKFILL_CONCURRENCY,
+            requests_per_second: None,
+        }
+    }
+}
+
+/// Spaces out requests so that no more than a given number start per second
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: Option<u32>) -> Self {
+        RateLimiter {
+            interval: requests_per_second
+                .filter(|rate| *rate > 0)
+                .map(|rate| Duration::from_secs(1) / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `requests` more requests may start
+    async fn acquire(&self, requests: u32) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+
+        let slot = {
+            let mut next = self
+                .next
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let slot = std::cmp::max(*next, Instant::now());
+            *next = slot + interval * requests;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// The chunks fetched together, and the confirmed block of every event they
+/// were planned against
+struct Window {
+    chunks: Vec<(U64, U64)>,
+    confirmed: Vec<U64>,
+}
+
+impl<T: Transport> EoServer<T> {
+    /// Catch up on the history up to the confirmed blocks with concurrent
+    /// `eth_getLogs` calls, delivering the events in chain order.
+    ///
+    /// Backfilling stops once what is left fits in a single chunk, or when a
+    /// chunk cannot be fetched. Live tailing then picks up at the next block
+    /// every event has not scanned yet, so nothing is skipped. Returns `true`
+    /// if the listener should stop, and fails if a checkpoint that has to be
+    /// saved before delivering cannot be.
+    pub(crate) async fn backfill(
+        &mut self,
+        events: &mpsc::Sender<EoEvent>,
+        shutdown: &mut Shutdown,
+    ) -> Result<bool, EoServerError> {
+        let Some(config) = self.backfill.clone() else {
+            return Ok(false);
+        };
+        let limiter = RateLimiter::new(config.requests_per_second);
+        let requests = self.registry.log_topics().len() as u32;
+
+        while !shutdown.is_stopped() {
+            if self.circuit_breaker.is_open() {
+                log::warn!("backfill paused by the circuit breaker, handing off to live tailing");
+                return Ok(false);
+            }
+
+            let window = match self.backfill_window(config.concurrency.max(1)).await {
+                Ok(Some(window)) => window,
+                Ok(None) => {
+                    log::info!("backfill caught up, handing off to live tailing");
+                    return Ok(false);
+                }
+                Err(err) => {
+                    log::warn!("backfill stopped, handing off to live tailing: {}", err);
+                    return Ok(false);
+                }
+            };
+            let contract_address = match self.eo_address.parse() {
+                Ok(address) => address,
+                Err(err) => {
+                    log::error!("backfill stopped: {}", err);
+                    return Ok(false);
+                }
+            };
+
+            if let (Some((from_block, _)), Some((_, to_block))) =
+                (window.chunks.first(), window.chunks.last())
+            {
+                log::info!(
+                    "backfilling blocks {} to {} in {} chunks",
+                    from_block,
+                    to_block,
+                    window.chunks.len()
+                );
+            }
+
+            let results = join_all(window.chunks.iter().map(|(from_block, to_block)| {
+                let limiter = &limiter;
+                let server = &*self;
+                async move {
+                    limiter.acquire(requests).await;
+                    server
+                        .fetch_logs(contract_address, *from_block, *to_block)
+                        .await
+                }
+            }))
+            .await;
+
+            for ((from_block, to_block), logs) in window.chunks.into_iter().zip(results) {
+                // Every earlier chunk has been completed, so each portion
+                // starts right after the end of the previous one
+                let portions = self
+                    .registry
+                    .iter()
+                    .zip(&window.confirmed)
+                    .map(|(event, confirmed)| {
+                        event.scheduler().portion(from_block, to_block, *confirmed)
+                    })
+                    .collect();
+                let plan = ScanPlan {
+                    range: (from_block, to_block),
+                    portions,
+                };
+
+                let chunk_sizes = self.chunk_sizes();
+                let (mut batch, decode_errors) = match self.process_logs(plan, logs) {
+                    Ok(decoded) => decoded,
+                    // The chunk size has shrunk, so plan the rest again.
+                    // Nothing after this chunk has been completed.
+                    Err(EoServerError::ProviderLimit { .. })
+                        if self.chunk_sizes() != chunk_sizes =>
+                    {
+                        break
+                    }
+                    Err(err) => {
+                        log::warn!("backfill stopped, handing off to live tailing: {}", err);
+                        return Ok(false);
+                    }
+                };
+                batch.sort_by_key(|event| (event.block_number(), event.log_index()));
+
+                if self.deliver_batch(events, batch).await? {
+                    log::info!("event receiver dropped, stopping");
+                    return Ok(true);
+                }
+                // The logs of an event that failed to decode would fail
+                // again, so stop instead of scanning past them
+                if let Some(err) = decode_errors.into_iter().next() {
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The next chunks to fetch, `None` once what is left fits in one
+    async fn backfill_window(
+        &mut self,
+        concurrency: usize,
+    ) -> Result<Option<Window>, EoServerError> {
+        let head = self.head().await?;
+        let confirmed = self.confirmed_blocks(head).await?;
+
+        let schedulers = self.registry.iter().map(RegisteredEvent::scheduler);
+        let Some(plan) = plan_scan(schedulers.zip(confirmed.iter().copied())) else {
+            return Ok(None);
+        };
+        let (from_block, first_end) = plan.range;
+        let end = confirmed.iter().copied().max().unwrap_or_default();
+        if first_end >= end {
+            return Ok(None);
+        }
+
+        // The first chunk is as long as the smallest chunk size of the
+        // events that are behind
+        let chunk_size = first_end - from_block + 1;
+        let mut chunks = Vec::with_capacity(concurrency);
+        let mut next = from_block;
+        while chunks.len() < concurrency && next <= end {
+            let to_block = std::cmp::min(next.saturating_add(chunk_size - 1), end);
+            chunks.push((next, to_block));
+            next = to_block + 1;
+        }
+
+        Ok(Some(Window { chunks, confirmed }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use web3::ethabi::Contract;
+
+    use super::*;
+    use crate::mock::{server_builder, MockTransport};
+    use crate::{BlobIndexSettledEvent, BridgeEvent, Confirmation, EventRegistry, RangeScheduler};
+
+    /// A server at head `head` whose events have the given confirmations,
+    /// with chunks of 100 blocks
+    fn server(head: u64, confirmations: [Confirmation; 2]) -> EoServer<MockTransport> {
+        let node = MockTransport::new(move |method, _| match method {
+            "eth_blockNumber" => Ok(json!(format!("{:#x}", head))),
+            _ => Err(web3::Error::Unreachable),
+        });
+        let abi = Contract::load(include_bytes!("../eo_contract_abi.json").as_slice()).unwrap();
+
+        let mut registry = EventRegistry::new();
+        for (name, confirmation) in [BridgeEvent::NAME, BlobIndexSettledEvent::NAME]
+            .into_iter()
+            .zip(confirmations)
+        {
+            let event = RegisteredEvent::from_abi(&abi, name)
+                .unwrap()
+                .with_confirmation(confirmation)
+                .with_scheduler(RangeScheduler::default().with_chunk_sizes(100, 1, 100));
+            registry.register(event).unwrap();
+        }
+
+        server_builder(node).registry(registry).build().unwrap()
+    }
+
+    fn blocks(ranges: &[(u64, u64)]) -> Vec<(U64, U64)> {
+        ranges
+            .iter()
+            .map(|(from, to)| (U64::from(*from), U64::from(*to)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn splits_the_window_into_chunks() {
+        let mut server = server(1_000, [Confirmation::Blocks(0); 2]);
+
+        let window = server.backfill_window(3).await.unwrap().unwrap();
+        assert_eq!(window.chunks, blocks(&[(0, 99), (100, 199), (200, 299)]));
+        assert_eq!(window.confirmed, vec![U64::from(1_000); 2]);
+    }
+
+    #[tokio::test]
+    async fn ends_the_window_at_the_highest_confirmed_block() {
+        let mut server = server(260, [Confirmation::Blocks(10), Confirmation::Blocks(50)]);
+
+        let window = server.backfill_window(4).await.unwrap().unwrap();
+        assert_eq!(window.chunks, blocks(&[(0, 99), (100, 199), (200, 250)]));
+        assert_eq!(window.confirmed, vec![U64::from(250), U64::from(210)]);
+    }
+
+    #[tokio::test]
+    async fn leaves_a_single_chunk_to_live_tailing() {
+        let mut short = server(99, [Confirmation::Blocks(0); 2]);
+        assert!(short.backfill_window(4).await.unwrap().is_none());
+
+        let mut server = server(1_000, [Confirmation::Blocks(0); 2]);
+        for event in server.registry.iter_mut() {
+            event.scheduler_mut().complete(U64::zero(), U64::from(950));
+        }
+        assert!(server.backfill_window(4).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn spaces_out_requests() {
+        let limiter = RateLimiter::new(Some(20));
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(1).await;
+        }
+        // The first starts right away, the others 50ms apart
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        limiter.acquire(2).await;
+        let before = Instant::now();
+        limiter.acquire(1).await;
+        assert!(before.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn does_not_wait_without_a_limit() {
+        for limiter in [RateLimiter::new(None), RateLimiter::new(Some(0))] {
+            let started = Instant::now();
+            for _ in 0..100 {
+                limiter.acquire(1).await;
+            }
+            assert!(started.elapsed() < Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use web3::types::{H256, U64};
+
+use crate::{
+    BlobIndexSettledEvent, BlockRanges, BridgeEvent, DeliveredEvents, EoEvent, EoServerError,
+};
+
+/// Prefix of every checkpoint written in the interval format. Legacy
+/// `BlocksProcessed` files start with a bincode `Option` tag (0 or 1), so
+/// they can never be mistaken for it.
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"EOCP";
+
+/// The format version written after the magic, bumped whenever the encoding
+/// of `Checkpoint` changes
+pub const CHECKPOINT_VERSION: u16 = 1;
+
+/// Magic, version and the keccak256 checksum of the payload
+const HEADER_LEN: usize = CHECKPOINT_MAGIC.len() + 2 + 32;
+
+/// The persisted progress of an `EoServer`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The progress of every registered event, by event name
+    pub events: BTreeMap<String, EventCheckpoint>,
+    /// The events delivered from recent blocks, so a restart does not
+    /// deliver them again
+    pub delivered: DeliveredEvents,
+    /// The hashes of recent blocks, so a reorg that happens while the
+    /// listener is stopped is still detected
+    pub block_hashes: BTreeMap<U64, H256>,
+    /// The events delivered from recent blocks by block, so a reorg found
+    /// after a restart can still retract them
+    pub recent_events: BTreeMap<U64, Vec<EoEvent>>,
+}
+
+/// Which blocks have been scanned for one event type
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventCheckpoint {
+    pub scanned: BlockRanges,
+    /// The highest block that has been scanned
+    pub high_water_mark: Option<U64>,
+    /// The block scanning started at, nothing before it is scanned after a
+    /// restart either
+    pub start_block: U64,
+}
+
+impl EventCheckpoint {
+    /// Progress that started at the first block in `scanned`, see
+    /// `with_start_block`
+    pub fn new(scanned: BlockRanges) -> Self {
+        EventCheckpoint {
+            high_water_mark: scanned.last_block(),
+            start_block: scanned.first_block().unwrap_or_default(),
+            scanned,
+        }
+    }
+
+    pub fn with_start_block(mut self, start_block: U64) -> Self {
+        self.start_block = start_block;
+        self
+    }
+
+    /// The block ranges between `from` and `to` (inclusive) that have not
+    /// been scanned yet
+    pub fn gaps(&self, from: U64, to: U64) -> Vec<(U64, U64)> {
+        self.scanned.gaps(from, to)
+    }
+}
+
+/// The checkpoint format written before block intervals were tracked. It
+/// kept every block that had a log, and the head seen on the last tick.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct BlocksProcessed {
+    pub bridge: Option<U64>,
+    pub settle: Option<U64>,
+    pub bridge_processed: BTreeSet<U64>,
+    pub settled_processed: BTreeSet<U64>,
+}
+
+impl From<BlocksProcessed> for Checkpoint {
+    /// The legacy format only proves that blocks up to the last one with a
+    /// log were scanned, so anything after it is scanned again.
+    fn from(legacy: BlocksProcessed) -> Self {
+        let scanned_through = |blocks: &BTreeSet<U64>| {
+            let mut scanned = BlockRanges::default();
+            if let Some(last) = blocks.last() {
+                scanned.insert(U64::zero(), *last);
+            }
+            EventCheckpoint::new(scanned)
+        };
+
+        Checkpoint {
+            events: BTreeMap::from([
+                (
+                    BridgeEvent::NAME.to_string(),
+                    scanned_through(&legacy.bridge_processed),
+                ),
+                (
+                    BlobIndexSettledEvent::NAME.to_string(),
+                    scanned_through(&legacy.settled_processed),
+                ),
+            ]),
+            ..Default::default()
+        }
+    }
+}
+
+impl Checkpoint {
+    /// The progress of the event called `name`
+    pub fn event(&self, name: &str) -> Option<&EventCheckpoint> {
+        self.events.get(name)
+    }
+
+    /// Encode as `magic | version (u16 LE) | keccak256(payload) | payload`
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        let payload = bincode::serialize(self)?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(CHECKPOINT_MAGIC);
+        bytes.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&Keccak256::digest(&payload));
+        bytes.extend(payload);
+        Ok(bytes)
+    }
+
+    /// Decode a checkpoint, migrating legacy `BlocksProcessed` files.
+    ///
+    /// Fails if the version is unknown or the payload does not match its
+    /// checksum, which is what a torn or truncated write looks like.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        let Some(rest) = bytes.strip_prefix(CHECKPOINT_MAGIC) else {
+            let legacy: BlocksProcessed = bincode::deserialize(bytes)?;
+            log::info!("migrating legacy blocks processed checkpoint");
+            return Ok(legacy.into());
+        };
+
+        if bytes.len() < HEADER_LEN {
+            return Err(corrupt("checkpoint header is truncated"));
+        }
+
+        let (version, rest) = rest.split_at(2);
+        let version = u16::from_le_bytes([version[0], version[1]]);
+        if version != CHECKPOINT_VERSION {
+            return Err(corrupt(&format!(
+                "unsupported checkpoint version {}, expected {}",
+                version, CHECKPOINT_VERSION
+            )));
+        }
+
+        let (checksum, payload) = rest.split_at(32);
+        if Keccak256::digest(payload).as_slice() != checksum {
+            return Err(corrupt("checkpoint checksum mismatch"));
+        }
+
+        bincode::deserialize(payload)
+    }
+
+    /// Read the checkpoint at `path`, falling back to the previous one kept
+    /// by `write_to` if the current file is missing or corrupt.
+    ///
+    /// Returns `Ok(None)` when neither file exists.
+    pub fn read_from(path: &Path) -> Result<Option<Self>, EoServerError> {
+        let previous = previous_path(path);
+
+        let err = match read_file(path) {
+            Ok(Some(checkpoint)) => return Ok(Some(checkpoint)),
+            Ok(None) => None,
+            Err(err) => Some(err),
+        };
+
+        match (err, read_file(&previous)) {
+            (err, Ok(Some(checkpoint))) => {
+                log::warn!(
+                    "failed to read checkpoint {}: {}, recovered from {}",
+                    path.display(),
+                    err.map_or_else(|| "not found".to_string(), |e| e.to_string()),
+                    previous.display()
+                );
+                Ok(Some(checkpoint))
+            }
+            (None, Ok(None)) => Ok(None),
+            (Some(err), Ok(None)) => Err(err),
+            (None, Err(prev_err)) => Err(prev_err),
+            (Some(err), Err(prev_err)) => {
+                log::error!("{}, and its previous copy: {}", err, prev_err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Atomically replace the checkpoint at `path`.
+    ///
+    /// The new checkpoint is written to a temporary file and fsynced before
+    /// it is renamed over `path`, so a crash leaves either the old or the new
+    /// checkpoint in place, never a partial one. The checkpoint being
+    /// replaced is kept next to it to recover from if `path` is later found
+    /// to be corrupt.
+    pub fn write_to(&self, path: &Path) -> Result<(), EoServerError> {
+        let context = || format!("failed to write checkpoint {}", path.display());
+        let bytes = self
+            .to_bytes()
+            .map_err(|e| EoServerError::checkpoint(context(), e))?;
+        let tmp = sibling_path(path, "tmp");
+
+        let write = || -> io::Result<()> {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            drop(file);
+
+            // Only keep the current checkpoint as a fallback if it is good,
+            // otherwise we would replace a good fallback with a corrupt one
+            if matches!(read_file(path), Ok(Some(_))) {
+                fs::rename(path, previous_path(path))?;
+            }
+            fs::rename(&tmp, path)?;
+            sync_dir(path)
+        };
+
+        write().map_err(|e| EoServerError::checkpoint(context(), e))
+    }
+}
+
+/// The checkpoint at `path`, or `None` if there is no file
+fn read_file(path: &Path) -> Result<Option<Checkpoint>, EoServerError> {
+    let context = || format!("failed to read checkpoint {}", path.display());
+
+    let mut bytes = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(EoServerError::checkpoint(context(), err)),
+    }
+
+    Checkpoint::from_bytes(&bytes)
+        .map(Some)
+        .map_err(|e| EoServerError::checkpoint(context(), e))
+}
+
+fn corrupt(reason: &str) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(reason.to_string()))
+}
+
+/// Where the checkpoint replaced by the last write is kept
+pub fn previous_path(path: &Path) -> PathBuf {
+    sibling_path(path, "prev")
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Persist the renames in `path`'s directory
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Bridge events scanned through block `last`
+#[cfg(test)]
+pub(crate) fn checkpoint(last: u64) -> Checkpoint {
+    let mut scanned = BlockRanges::default();
+    scanned.insert(U64::zero(), U64::from(last));
+    Checkpoint {
+        events: BTreeMap::from([(BridgeEvent::NAME.to_string(), EventCheckpoint::new(scanned))]),
+        ..Default::default()
+    }
+}
+
+/// A fresh path in the temp dir, without leftovers of earlier runs
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("eo_listener_{}_{}", std::process::id(), name));
+    for path in [
+        path.clone(),
+        previous_path(&path),
+        sibling_path(&path, "tmp"),
+    ] {
+        let _ = fs::remove_file(path);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let mut checkpoint = checkpoint(100);
+        // Contract events carry decoded ABI tokens, the least trivial
+        // thing in a checkpoint to encode
+        let event = crate::ContractEvent {
+            name: "Transfer".to_string(),
+            params: vec![web3::ethabi::LogParam {
+                name: "value".to_string(),
+                value: web3::ethabi::Token::Uint(1.into()),
+            }],
+            block_hash: H256::repeat_byte(1),
+            tx_hash: H256::repeat_byte(2),
+            log_index: Default::default(),
+            block_number: U64::from(100),
+        };
+        checkpoint
+            .block_hashes
+            .insert(U64::from(100), H256::repeat_byte(1));
+        checkpoint
+            .recent_events
+            .insert(U64::from(100), vec![EoEvent::Contract(event)]);
+
+        let bytes = checkpoint.to_bytes().unwrap();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn rejects_a_truncated_checkpoint() {
+        let bytes = checkpoint(100).to_bytes().unwrap();
+        assert!(Checkpoint::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Checkpoint::from_bytes(&bytes[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_a_flipped_payload_byte() {
+        let mut bytes = checkpoint(100).to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        let err = Checkpoint::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn rejects_an_unknown_version() {
+        let mut bytes = checkpoint(100).to_bytes().unwrap();
+        let version = (CHECKPOINT_VERSION + 1).to_le_bytes();
+        bytes[CHECKPOINT_MAGIC.len()..CHECKPOINT_MAGIC.len() + 2].copy_from_slice(&version);
+
+        let err = Checkpoint::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("unsupported checkpoint version"));
+    }
+
+    #[test]
+    fn migrates_a_legacy_blocks_processed_file() {
+        let legacy = BlocksProcessed {
+            bridge: Some(U64::from(500)),
+            settle: Some(U64::from(500)),
+            bridge_processed: BTreeSet::from([U64::from(7), U64::from(42)]),
+            settled_processed: BTreeSet::new(),
+        };
+        let bytes = bincode::serialize(&legacy).unwrap();
+
+        let checkpoint = Checkpoint::from_bytes(&bytes).unwrap();
+        let bridge = checkpoint.event(BridgeEvent::NAME).unwrap();
+        assert_eq!(
+            bridge.scanned.iter().collect::<Vec<_>>(),
+            vec![(U64::zero(), U64::from(42))]
+        );
+        let settlement = checkpoint.event(BlobIndexSettledEvent::NAME).unwrap();
+        assert!(settlement.scanned.is_empty());
+    }
+
+    #[test]
+    fn recovers_from_the_previous_checkpoint() {
+        let path = temp_path("recovers.dat");
+        checkpoint(100).write_to(&path).unwrap();
+        checkpoint(200).write_to(&path).unwrap();
+        assert_eq!(Checkpoint::read_from(&path).unwrap(), Some(checkpoint(200)));
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(Checkpoint::read_from(&path).unwrap(), Some(checkpoint(100)));
+
+        // A corrupt checkpoint never replaces a good fallback
+        checkpoint(300).write_to(&path).unwrap();
+        assert_eq!(
+            Checkpoint::read_from(&previous_path(&path)).unwrap(),
+            Some(checkpoint(100))
+        );
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(previous_path(&path));
+    }
+
+    #[test]
+    fn reads_nothing_without_a_checkpoint() {
+        let path = temp_path("missing.dat");
+        assert_eq!(Checkpoint::read_from(&path).unwrap(), None);
+    }
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use web3::{
+    api::Eth,
+    types::{BlockId, BlockNumber, U64},
+    Transport,
+};
+
+use crate::EoServerError;
+
+/// How far behind the chain head an event has to be before it is released
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Confirmation {
+    /// Release events once they are this many blocks behind the latest
+    /// block, `Blocks(0)` releases them as soon as they are mined
+    Blocks(u64),
+    /// Release events up to the block tagged `safe`
+    Safe,
+    /// Release events up to the block tagged `finalized`
+    Finalized,
+}
+
+impl Default for Confirmation {
+    fn default() -> Self {
+        Confirmation::Blocks(0)
+    }
+}
+
+impl Confirmation {
+    /// The highest block whose events may be released under this setting,
+    /// given the latest block number. `Blocks` needs no RPC call at all.
+    pub async fn confirmed_block_at<T: Transport>(
+        &self,
+        eth: &Eth<T>,
+        head: U64,
+    ) -> Result<U64, EoServerError> {
+        let tag = match self {
+            Confirmation::Blocks(depth) => return Ok(head.saturating_sub(U64::from(*depth))),
+            Confirmation::Safe => BlockNumber::Safe,
+            Confirmation::Finalized => BlockNumber::Finalized,
+        };
+
+        eth.block(BlockId::Number(tag))
+            .await
+            .map_err(|e| EoServerError::rpc(format!("failed to get the {:?} block", tag), e))?
+            .and_then(|block| block.number)
+            .ok_or_else(|| {
+                EoServerError::InvalidResponse(format!("node did not return the {:?} block", tag))
+            })
+    }
+}
+
+impl std::str::FromStr for Confirmation {
+    type Err = EoServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "latest" => Ok(Confirmation::Blocks(0)),
+            "safe" => Ok(Confirmation::Safe),
+            "finalized" => Ok(Confirmation::Finalized),
+            depth => depth.parse().map(Confirmation::Blocks).map_err(|_| {
+                EoServerError::Config(format!(
+                    "Invalid confirmation setting {:?}, expected a block count, \"latest\", \"safe\" or \"finalized\"",
+                    s
+                ))
+            }),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use web3::types::U64;
+
+use crate::EventId;
+
+/// The IDs of the events delivered from recent blocks, by block number.
+///
+/// Scans of overlapping ranges, a catch-up racing a subscription or a
+/// restart all may decode a log that was already delivered. Its ID is found
+/// here and it is skipped. Only the blocks a reorg can still affect are
+/// kept, older ones are covered by the scanned ranges of the checkpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveredEvents(BTreeMap<U64, BTreeSet<EventId>>);
+
+impl DeliveredEvents {
+    /// Record a delivered event, returning `false` if it was delivered before
+    pub fn insert(&mut self, block: U64, id: EventId) -> bool {
+        self.0.entry(block).or_default().insert(id)
+    }
+
+    pub fn contains(&self, block: U64, id: &EventId) -> bool {
+        self.0.get(&block).is_some_and(|ids| ids.contains(id))
+    }
+
+    /// Forget a retracted event, so it is delivered again if it is included
+    /// again
+    pub fn remove(&mut self, block: U64, id: &EventId) {
+        if let Some(ids) = self.0.get_mut(&block) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.0.remove(&block);
+            }
+        }
+    }
+
+    /// Forget every event from `block` onwards
+    pub fn remove_from(&mut self, block: U64) {
+        self.0.split_off(&block);
+    }
+
+    /// Forget every event before `block`
+    pub fn prune_before(&mut self, block: U64) {
+        self.0 = self.0.split_off(&block);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.values().map(BTreeSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use web3::Transport;
+
+use crate::{deliver, Checkpoint, EoEvent, EoServer, EoServerError};
+
+/// How often the checkpoint is saved when no batch triggers it
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a stopping listener waits for the consumer to acknowledge the
+/// events it has received before saving its final checkpoint
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many checkpoints wait for acknowledgements at most. Beyond that the
+/// ones right after the oldest are dropped, so a consumer that falls behind
+/// (or never acknowledges) costs a coarser checkpoint instead of memory.
+const MAX_PENDING_CHECKPOINTS: usize = 64;
+
+/// How often a stopping listener checks for acknowledgements
+const ACK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What a consumer can expect after the listener crashes and restarts
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryGuarantee {
+    /// The checkpoint only covers events the consumer has acknowledged with
+    /// an `Acknowledger`, anything else is delivered again after a restart
+    #[default]
+    AtLeastOnce,
+    /// The checkpoint is saved before each batch is delivered, so a batch
+    /// that was in flight during a crash is never delivered again
+    AtMostOnce,
+}
+
+impl std::str::FromStr for DeliveryGuarantee {
+    type Err = EoServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().replace('_', "-").as_str() {
+            "at-least-once" => Ok(DeliveryGuarantee::AtLeastOnce),
+            "at-most-once" => Ok(DeliveryGuarantee::AtMostOnce),
+            _ => Err(EoServerError::Config(format!(
+                "Invalid delivery guarantee {:?}, expected \"at-least-once\" or \"at-most-once\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Tells the listener which delivered events the consumer has finished
+/// with. Events are acknowledged in the order they were received.
+#[derive(Clone, Debug, Default)]
+pub struct Acknowledger {
+    acked: Arc<AtomicU64>,
+}
+
+impl Acknowledger {
+    /// Acknowledge the oldest event that has not been acknowledged yet
+    pub fn ack(&self) {
+        self.ack_many(1)
+    }
+
+    /// Acknowledge the `count` oldest events that have not been acknowledged
+    pub fn ack_many(&self, count: u64) {
+        self.acked.fetch_add(count, Ordering::AcqRel);
+    }
+
+    fn acked(&self) -> u64 {
+        self.acked.load(Ordering::Acquire)
+    }
+}
+
+/// Checkpoints taken after each delivered batch, waiting for the consumer to
+/// acknowledge every event delivered before them
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CheckpointTracker {
+    acks: Acknowledger,
+    delivered: u64,
+    pending: VecDeque<(u64, Checkpoint)>,
+    /// The newest checkpoint that may be saved but has not been yet
+    ready: Option<Checkpoint>,
+    saved: Option<Checkpoint>,
+    saved_at: Option<Instant>,
+}
+
+impl CheckpointTracker {
+    /// Record that `count` more events were delivered and that `checkpoint`
+    /// may be saved once they are acknowledged
+    fn delivered(&mut self, count: u64, checkpoint: Checkpoint) {
+        self.delivered += count;
+        match self.pending.back_mut() {
+            // Nothing was delivered since the last checkpoint, so this one
+            // supersedes it
+            Some((delivered, last)) if *delivered == self.delivered => *last = checkpoint,
+            _ => self.pending.push_back((self.delivered, checkpoint)),
+        }
+
+        // Keep the oldest, it is the next one to be acknowledged
+        if self.pending.len() > MAX_PENDING_CHECKPOINTS {
+            self.pending.remove(1);
+        }
+    }
+
+    /// Whether every delivered event has been acknowledged
+    fn is_acked(&self) -> bool {
+        self.acks.acked() >= self.delivered
+    }
+
+    /// Move the newest fully acknowledged checkpoint to `ready`
+    fn collect_acked(&mut self) {
+        let acked = self.acks.acked();
+        while let Some((delivered, _)) = self.pending.front() {
+            if *delivered > acked {
+                break;
+            }
+            self.ready = self.pending.pop_front().map(|(_, checkpoint)| checkpoint);
+        }
+    }
+
+    fn is_due(&self, interval: Duration) -> bool {
+        self.saved_at.is_none_or(|at| at.elapsed() >= interval)
+    }
+}
+
+impl<T: Transport> EoServer<T> {
+    /// Acknowledges events delivered by this server, see `DeliveryGuarantee`
+    pub fn acknowledger(&self) -> Acknowledger {
+        self.checkpoints.acks.clone()
+    }
+
+    /// Deliver `batch` and checkpoint according to the delivery guarantee:
+    /// after every batch that had events and every `checkpoint_interval`.
+    ///
+    /// Returns `true` once the receiver has been dropped. Under `AtMostOnce`
+    /// a batch whose checkpoint cannot be saved is not delivered, the save
+    /// error is returned instead and the batch is scanned again on restart.
+    pub(crate) async fn deliver_batch(
+        &mut self,
+        events: &mpsc::Sender<EoEvent>,
+        batch: Vec<EoEvent>,
+    ) -> Result<bool, EoServerError> {
+        let count = batch.len() as u64;
+        match self.delivery {
+            DeliveryGuarantee::AtMostOnce => {
+                if count > 0 {
+                    self.commit().await?;
+                }
+                if deliver(events, batch).await.is_err() {
+                    return Ok(true);
+                }
+            }
+            DeliveryGuarantee::AtLeastOnce => {
+                if deliver(events, batch).await.is_err() {
+                    return Ok(true);
+                }
+                let checkpoint = self.checkpoint();
+                self.checkpoints.delivered(count, checkpoint);
+                if count > 0 {
+                    self.commit_logged().await;
+                }
+            }
+        }
+
+        if self.checkpoints.is_due(self.checkpoint_interval) {
+            self.commit_logged().await;
+        }
+
+        Ok(false)
+    }
+
+    /// Give the consumer up to `ack_timeout` to acknowledge the events it
+    /// has received, so the final checkpoint covers them. Returns early if
+    /// the receiver is dropped, nothing it did not acknowledge is lost then.
+    pub(crate) async fn wait_for_acks(&self, events: &mpsc::Sender<EoEvent>) {
+        if self.delivery != DeliveryGuarantee::AtLeastOnce || self.checkpoints.is_acked() {
+            return;
+        }
+
+        log::info!("waiting for the consumer to acknowledge delivered events");
+        let deadline = Instant::now() + self.ack_timeout;
+        while !self.checkpoints.is_acked() && !events.is_closed() {
+            if Instant::now() >= deadline {
+                log::warn!(
+                    "events still unacknowledged after {:?}, they are delivered again after a restart",
+                    self.ack_timeout
+                );
+                return;
+            }
+            tokio::time::sleep(ACK_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Save the newest checkpoint the delivery guarantee allows, if it has
+    /// changed since the last save. Stores block on disk or on a database
+    /// lock, so the save runs on the blocking thread pool.
+    pub(crate) async fn commit(&mut self) -> Result<(), EoServerError> {
+        match self.delivery {
+            DeliveryGuarantee::AtMostOnce => self.checkpoints.ready = Some(self.checkpoint()),
+            DeliveryGuarantee::AtLeastOnce => self.checkpoints.collect_acked(),
+        }
+        self.checkpoints.saved_at = Some(Instant::now());
+
+        let Some(checkpoint) = self.checkpoints.ready.take() else {
+            return Ok(());
+        };
+        if self.checkpoints.saved.as_ref() == Some(&checkpoint) {
+            return Ok(());
+        }
+
+        let store = self.checkpoint_store.clone();
+        let saving = tokio::task::spawn_blocking(move || {
+            let saved = store.save(&checkpoint);
+            (checkpoint, saved)
+        });
+        let (checkpoint, saved) = match saving.await {
+            Ok(saving) => saving,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        };
+        if let Err(err) = saved {
+            // Keep it around to retry on the next commit, unless a newer
+            // one replaces it first
+            self.checkpoints.ready = Some(checkpoint);
+            return Err(err);
+        }
+        self.checkpoints.saved = Some(checkpoint);
+
+        Ok(())
+    }
+
+    async fn commit_logged(&mut self) {
+        if let Err(err) = self.commit().await {
+            log::error!("failed to save checkpoint: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::checkpoint;
+
+    #[test]
+    fn readies_the_newest_acknowledged_checkpoint() {
+        let mut tracker = CheckpointTracker::default();
+        tracker.delivered(2, checkpoint(10));
+        tracker.delivered(3, checkpoint(20));
+
+        tracker.acks.ack_many(4);
+        tracker.collect_acked();
+        assert_eq!(tracker.ready, Some(checkpoint(10)));
+        assert!(!tracker.is_acked());
+
+        tracker.acks.ack();
+        tracker.collect_acked();
+        assert_eq!(tracker.ready, Some(checkpoint(20)));
+        assert!(tracker.is_acked());
+    }
+
+    #[test]
+    fn bounds_the_checkpoints_waiting_for_acks() {
+        let mut tracker = CheckpointTracker::default();
+        for last in 1..=1000 {
+            tracker.delivered(1, checkpoint(last));
+        }
+        assert_eq!(tracker.pending.len(), MAX_PENDING_CHECKPOINTS);
+
+        // The oldest is kept, so the first ack still readies a checkpoint
+        tracker.acks.ack();
+        tracker.collect_acked();
+        assert_eq!(tracker.ready, Some(checkpoint(1)));
+
+        tracker.acks.ack_many(999);
+        tracker.collect_acked();
+        assert_eq!(tracker.ready, Some(checkpoint(1000)));
+    }
+}
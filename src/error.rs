@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use web3::types::{H256, U256, U64};
+
+use crate::EoServerBuilderError;
+
+type Source = Arc<dyn std::error::Error + Send + Sync>;
+
+/// Everything that can go wrong while listening for events.
+///
+/// `is_transient` tells errors worth retrying, like a dropped connection or
+/// a rejected block range, from ones that will fail the same way again.
+#[derive(Clone, Debug)]
+pub enum EoServerError {
+    /// An RPC call to the node failed
+    Rpc {
+        context: String,
+        source: Arc<web3::Error>,
+    },
+    /// The provider rejected an `eth_getLogs` range for being too large or
+    /// returning too many results
+    ProviderLimit {
+        from_block: U64,
+        to_block: U64,
+        source: Arc<web3::Error>,
+    },
+    /// The node answered, but without something we need from it, like the
+    /// number of a block
+    InvalidResponse(String),
+    /// A log could not be decoded into an event
+    Decode {
+        tx_hash: Option<H256>,
+        log_index: Option<U256>,
+        reason: String,
+    },
+    /// The contract ABI could not be parsed or is missing an event
+    Abi {
+        reason: String,
+        source: Option<Arc<web3::ethabi::Error>>,
+    },
+    /// A checkpoint could not be read or written
+    Checkpoint {
+        context: String,
+        source: Source,
+    },
+    /// A setting is missing or invalid
+    Config(String),
+    /// A contract address is not valid hex
+    InvalidAddress {
+        address: String,
+        source: rustc_hex::FromHexError,
+    },
+    Other(String),
+}
+
+impl EoServerError {
+    pub fn rpc(context: impl Into<String>, source: web3::Error) -> Self {
+        EoServerError::Rpc {
+            context: context.into(),
+            source: Arc::new(source),
+        }
+    }
+
+    pub fn checkpoint(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        EoServerError::Checkpoint {
+            context: context.into(),
+            source: Arc::from(source.into()),
+        }
+    }
+
+    pub fn abi(reason: impl Into<String>, source: web3::ethabi::Error) -> Self {
+        EoServerError::Abi {
+            reason: reason.into(),
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    pub fn invalid_address(address: impl Into<String>, source: rustc_hex::FromHexError) -> Self {
+        EoServerError::InvalidAddress {
+            address: address.into(),
+            source,
+        }
+    }
+
+    /// Whether the same operation may succeed if it is retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EoServerError::Rpc { source, .. } => crate::rpc::is_transient_rpc_error(source),
+            EoServerError::ProviderLimit { .. }
+            | EoServerError::InvalidResponse(_)
+            | EoServerError::Checkpoint { .. } => true,
+            EoServerError::Decode { .. }
+            | EoServerError::Abi { .. }
+            | EoServerError::Config(_)
+            | EoServerError::InvalidAddress { .. }
+            | EoServerError::Other(_) => false,
+        }
+    }
+}
+
+impl From<web3::Error> for EoServerError {
+    fn from(value: web3::Error) -> Self {
+        EoServerError::rpc("RPC call failed", value)
+    }
+}
+
+impl From<EoServerBuilderError> for EoServerError {
+    fn from(value: EoServerBuilderError) -> Self {
+        EoServerError::Config(value.to_string())
+    }
+}
+
+impl std::fmt::Display for EoServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EoServerError::Rpc { context, source } => write!(f, "{}: {}", context, source),
+            EoServerError::ProviderLimit {
+                from_block,
+                to_block,
+                source,
+            } => write!(
+                f,
+                "provider rejected blocks {} to {}: {}",
+                from_block, to_block, source
+            ),
+            EoServerError::InvalidResponse(reason) => write!(f, "invalid response: {}", reason),
+            EoServerError::Decode {
+                tx_hash,
+                log_index,
+                reason,
+            } => write!(
+                f,
+                "failed to decode log: tx_hash = {:?}, log_index = {:?}: {}",
+                tx_hash, log_index, reason
+            ),
+            EoServerError::Abi { reason, .. } => write!(f, "invalid ABI: {}", reason),
+            EoServerError::Checkpoint { context, source } => write!(f, "{}: {}", context, source),
+            EoServerError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+            EoServerError::InvalidAddress { address, source } => {
+                write!(f, "invalid address {:?}: {}", address, source)
+            }
+            EoServerError::Other(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for EoServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EoServerError::Rpc { source, .. } | EoServerError::ProviderLimit { source, .. } => {
+                Some(source.as_ref())
+            }
+            EoServerError::Abi {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            EoServerError::Checkpoint { source, .. } => Some(source.as_ref()),
+            EoServerError::InvalidAddress { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
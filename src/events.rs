@@ -0,0 +1,393 @@
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use web3::ethabi::{Event as AbiEvent, Log as AbiLog, LogParam, RawLog, Token};
+use web3::types::{Address, Log, H256, U256, U64};
+
+use crate::EoServerError;
+
+/// A `Bridge` event emitted when a user deposits into the Executable Oracle
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BridgeEvent {
+    pub user: Address,
+    pub token_address: Address,
+    pub amount: U256,
+    pub token_id: U256,
+    pub token_type: String,
+    pub bridge_event_id: U256,
+    pub block_hash: H256,
+    pub tx_hash: H256,
+    pub log_index: U256,
+    pub block_number: U64,
+}
+
+/// A `BlobIndexSettled` event emitted when a batch is settled.
+///
+/// `accounts` is an indexed dynamic array, so the log only carries the
+/// keccak256 hash of its ABI encoding and not the addresses themselves.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlobIndexSettledEvent {
+    pub accounts: H256,
+    pub batch_header_hash: H256,
+    pub blob_index: u128,
+    pub blob_event_id: U256,
+    pub block_hash: H256,
+    pub tx_hash: H256,
+    pub log_index: U256,
+    pub block_number: U64,
+}
+
+/// Any other contract event, with its parameters as decoded from the ABI
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ContractEvent {
+    pub name: String,
+    pub params: Vec<LogParam>,
+    pub block_hash: H256,
+    pub tx_hash: H256,
+    pub log_index: U256,
+    pub block_number: U64,
+}
+
+/// Identifies one inclusion of a log in the chain, the keccak256 of its
+/// block hash, transaction hash and log index.
+///
+/// A log always gets the same ID, no matter how often it is scanned or how
+/// many times the listener restarts, so consumers can use it as an
+/// idempotency key. A log that a reorg moves into another block gets a new
+/// one.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EventId(pub H256);
+
+impl EventId {
+    pub fn new(block_hash: H256, tx_hash: H256, log_index: U256) -> Self {
+        let mut hasher = Keccak256::new();
+        hasher.update(block_hash.as_bytes());
+        hasher.update(tx_hash.as_bytes());
+        let mut index = [0u8; 32];
+        log_index.to_big_endian(&mut index);
+        hasher.update(index);
+        EventId(H256::from_slice(&hasher.finalize()))
+    }
+}
+
+impl std::fmt::Display for EventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum EoEvent {
+    Bridge(BridgeEvent),
+    Settlement(BlobIndexSettledEvent),
+    Contract(ContractEvent),
+    /// Retracts a previously delivered event whose block was reorged out of
+    /// the canonical chain
+    Removed(Box<EoEvent>),
+}
+
+impl EoEvent {
+    pub fn tx_hash(&self) -> H256 {
+        match self {
+            EoEvent::Bridge(e) => e.tx_hash,
+            EoEvent::Settlement(e) => e.tx_hash,
+            EoEvent::Contract(e) => e.tx_hash,
+            EoEvent::Removed(e) => e.tx_hash(),
+        }
+    }
+
+    pub fn log_index(&self) -> U256 {
+        match self {
+            EoEvent::Bridge(e) => e.log_index,
+            EoEvent::Settlement(e) => e.log_index,
+            EoEvent::Contract(e) => e.log_index,
+            EoEvent::Removed(e) => e.log_index(),
+        }
+    }
+
+    pub fn block_number(&self) -> U64 {
+        match self {
+            EoEvent::Bridge(e) => e.block_number,
+            EoEvent::Settlement(e) => e.block_number,
+            EoEvent::Contract(e) => e.block_number,
+            EoEvent::Removed(e) => e.block_number(),
+        }
+    }
+
+    pub fn block_hash(&self) -> H256 {
+        match self {
+            EoEvent::Bridge(e) => e.block_hash,
+            EoEvent::Settlement(e) => e.block_hash,
+            EoEvent::Contract(e) => e.block_hash,
+            EoEvent::Removed(e) => e.block_hash(),
+        }
+    }
+
+    /// The ID of the log this event was decoded from. A retraction has the
+    /// ID of the event it retracts.
+    pub fn id(&self) -> EventId {
+        EventId::new(self.block_hash(), self.tx_hash(), self.log_index())
+    }
+
+    /// The name of the contract event
+    pub fn name(&self) -> &str {
+        match self {
+            EoEvent::Bridge(_) => BridgeEvent::NAME,
+            EoEvent::Settlement(_) => BlobIndexSettledEvent::NAME,
+            EoEvent::Contract(e) => &e.name,
+            EoEvent::Removed(e) => e.name(),
+        }
+    }
+
+    pub fn is_removed(&self) -> bool {
+        matches!(self, EoEvent::Removed(_))
+    }
+}
+
+impl BridgeEvent {
+    pub const NAME: &'static str = "Bridge";
+    pub const SIGNATURE: &'static str = "Bridge(address,address,uint256,uint256,string,uint256)";
+
+    pub fn from_log(log: &Log, event_abi: &AbiEvent) -> Result<Self, EoServerError> {
+        let (parsed, meta) = parse_log(log, event_abi)?;
+        Ok(BridgeEvent {
+            user: param(&parsed, log, "user", Token::into_address)?,
+            token_address: param(&parsed, log, "tokenAddress", Token::into_address)?,
+            amount: param(&parsed, log, "amount", Token::into_uint)?,
+            token_id: param(&parsed, log, "tokenId", Token::into_uint)?,
+            token_type: param(&parsed, log, "tokenType", Token::into_string)?,
+            bridge_event_id: param(&parsed, log, "bridgeEventId", Token::into_uint)?,
+            block_hash: meta.block_hash,
+            tx_hash: meta.tx_hash,
+            log_index: meta.log_index,
+            block_number: meta.block_number,
+        })
+    }
+}
+
+impl BlobIndexSettledEvent {
+    pub const NAME: &'static str = "BlobIndexSettled";
+    pub const SIGNATURE: &'static str = "BlobIndexSettled(address[],bytes32,uint128,uint256)";
+
+    pub fn from_log(log: &Log, event_abi: &AbiEvent) -> Result<Self, EoServerError> {
+        let (parsed, meta) = parse_log(log, event_abi)?;
+        let blob_index = param(&parsed, log, "blobIndex", Token::into_uint)?;
+        if blob_index.bits() > 128 {
+            return Err(decode_error(log, "`blobIndex` does not fit in a uint128"));
+        }
+
+        Ok(BlobIndexSettledEvent {
+            accounts: param(&parsed, log, "accounts", into_h256)?,
+            batch_header_hash: param(&parsed, log, "batchHeaderHash", into_h256)?,
+            blob_index: blob_index.as_u128(),
+            blob_event_id: param(&parsed, log, "blobEventId", Token::into_uint)?,
+            block_hash: meta.block_hash,
+            tx_hash: meta.tx_hash,
+            log_index: meta.log_index,
+            block_number: meta.block_number,
+        })
+    }
+}
+
+impl ContractEvent {
+    pub fn from_log(log: &Log, event_abi: &AbiEvent) -> Result<Self, EoServerError> {
+        let (parsed, meta) = parse_log(log, event_abi)?;
+        Ok(ContractEvent {
+            name: event_abi.name.clone(),
+            params: parsed.params,
+            block_hash: meta.block_hash,
+            tx_hash: meta.tx_hash,
+            log_index: meta.log_index,
+            block_number: meta.block_number,
+        })
+    }
+}
+
+struct LogMeta {
+    block_hash: H256,
+    tx_hash: H256,
+    log_index: U256,
+    block_number: U64,
+}
+
+fn parse_log(log: &Log, event_abi: &AbiEvent) -> Result<(AbiLog, LogMeta), EoServerError> {
+    let meta = LogMeta {
+        block_hash: log
+            .block_hash
+            .ok_or_else(|| decode_error(log, "log missing block hash"))?,
+        tx_hash: log
+            .transaction_hash
+            .ok_or_else(|| decode_error(log, "log missing transaction hash"))?,
+        log_index: log
+            .log_index
+            .ok_or_else(|| decode_error(log, "log missing log index"))?,
+        block_number: log
+            .block_number
+            .ok_or_else(|| decode_error(log, "log missing block number"))?,
+    };
+
+    let parsed = event_abi
+        .parse_log(RawLog {
+            topics: log.topics.clone(),
+            data: log.data.0.clone(),
+        })
+        .map_err(|e| decode_error(log, e.to_string()))?;
+
+    Ok((parsed, meta))
+}
+
+fn param<T>(
+    parsed: &AbiLog,
+    log: &Log,
+    name: &str,
+    convert: fn(Token) -> Option<T>,
+) -> Result<T, EoServerError> {
+    let token = parsed
+        .params
+        .iter()
+        .find(|p| p.name == name)
+        .map(|p| p.value.clone())
+        .ok_or_else(|| decode_error(log, format!("missing `{}` parameter", name)))?;
+
+    convert(token).ok_or_else(|| decode_error(log, format!("unexpected type for `{}`", name)))
+}
+
+fn into_h256(token: Token) -> Option<H256> {
+    token
+        .into_fixed_bytes()
+        .filter(|b| b.len() == 32)
+        .map(|b| H256::from_slice(&b))
+}
+
+pub(crate) fn decode_error(log: &Log, reason: impl Into<String>) -> EoServerError {
+    EoServerError::Decode {
+        tx_hash: log.transaction_hash,
+        log_index: log.log_index,
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use web3::ethabi::{encode, Contract};
+    use web3::types::Bytes;
+
+    use super::*;
+
+    fn abi(name: &str) -> AbiEvent {
+        let abi = Contract::load(include_bytes!("../eo_contract_abi.json").as_slice()).unwrap();
+        abi.event(name).unwrap().clone()
+    }
+
+    fn log(abi: &AbiEvent, indexed: Vec<H256>, data: Vec<Token>) -> Log {
+        let mut topics = vec![abi.signature()];
+        topics.extend(indexed);
+        Log {
+            address: Address::repeat_byte(0xee),
+            topics,
+            data: Bytes(encode(&data)),
+            block_hash: Some(H256::repeat_byte(1)),
+            block_number: Some(U64::from(42)),
+            transaction_hash: Some(H256::repeat_byte(2)),
+            transaction_index: Some(U64::zero()),
+            log_index: Some(U256::from(3)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        }
+    }
+
+    fn settled_log(blob_index: U256) -> Log {
+        log(
+            &abi(BlobIndexSettledEvent::NAME),
+            vec![H256::repeat_byte(0xaa)],
+            vec![
+                Token::FixedBytes(vec![0xbb; 32]),
+                Token::Uint(blob_index),
+                Token::Uint(U256::from(9)),
+            ],
+        )
+    }
+
+    #[test]
+    fn decodes_a_bridge_log() {
+        let abi = abi(BridgeEvent::NAME);
+        let user = Address::repeat_byte(0x11);
+        let token_address = Address::repeat_byte(0x22);
+        let log = log(
+            &abi,
+            vec![user.into(), token_address.into()],
+            vec![
+                Token::Uint(U256::from(1_000)),
+                Token::Uint(U256::from(7)),
+                Token::String("erc721".to_string()),
+                Token::Uint(U256::from(99)),
+            ],
+        );
+
+        assert_eq!(
+            BridgeEvent::from_log(&log, &abi).unwrap(),
+            BridgeEvent {
+                user,
+                token_address,
+                amount: U256::from(1_000),
+                token_id: U256::from(7),
+                token_type: "erc721".to_string(),
+                bridge_event_id: U256::from(99),
+                block_hash: H256::repeat_byte(1),
+                tx_hash: H256::repeat_byte(2),
+                log_index: U256::from(3),
+                block_number: U64::from(42),
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_a_blob_index_settled_log() {
+        let abi = abi(BlobIndexSettledEvent::NAME);
+        let blob_index = U256::from(u128::MAX);
+
+        assert_eq!(
+            BlobIndexSettledEvent::from_log(&settled_log(blob_index), &abi).unwrap(),
+            BlobIndexSettledEvent {
+                accounts: H256::repeat_byte(0xaa),
+                batch_header_hash: H256::repeat_byte(0xbb),
+                blob_index: u128::MAX,
+                blob_event_id: U256::from(9),
+                block_hash: H256::repeat_byte(1),
+                tx_hash: H256::repeat_byte(2),
+                log_index: U256::from(3),
+                block_number: U64::from(42),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_a_blob_index_beyond_uint128() {
+        let abi = abi(BlobIndexSettledEvent::NAME);
+        let log = settled_log(U256::from(u128::MAX) + 1);
+
+        let err = BlobIndexSettledEvent::from_log(&log, &abi).unwrap_err();
+        assert!(err.to_string().contains("uint128"), "{}", err);
+    }
+
+    #[test]
+    fn reports_where_a_malformed_log_came_from() {
+        let abi = abi(BridgeEvent::NAME);
+        // The indexed token address is missing
+        let log = log(
+            &abi,
+            vec![H256::repeat_byte(0x11)],
+            vec![Token::Uint(U256::from(1_000))],
+        );
+
+        match BridgeEvent::from_log(&log, &abi) {
+            Err(EoServerError::Decode {
+                tx_hash, log_index, ..
+            }) => {
+                assert_eq!(tx_hash, Some(H256::repeat_byte(2)));
+                assert_eq!(log_index, Some(U256::from(3)));
+            }
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+}
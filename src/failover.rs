@@ -0,0 +1,597 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{join_all, BoxFuture};
+use jsonrpc_core::{Call, Value};
+use web3::{
+    error::TransportError,
+    helpers::build_request,
+    types::{Bytes, Log, H160, H256, U256, U64},
+    RequestId, Transport,
+};
+
+use crate::rpc::is_transient_rpc_error;
+
+/// How many calls in a row an endpoint may fail before it is marked down
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+/// How long an endpoint that was marked down is avoided
+pub const DEFAULT_DOWN_FOR: Duration = Duration::from_secs(30);
+/// How many blocks an endpoint may trail the highest head before it is
+/// treated as lagging
+pub const DEFAULT_MAX_HEAD_LAG: u64 = 5;
+/// How often the heads of all endpoints are compared
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a single endpoint may take to answer before the call fails over
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A transport over several RPC endpoints for the same chain.
+///
+/// Calls go to the first healthy endpoint in the order they were given and
+/// fail over to the next one when it errors. Endpoints that keep failing are
+/// marked down for a while, and endpoints whose head trails the others by
+/// more than `max_head_lag` blocks are only used when nothing better is
+/// left. Heads are compared in the background, never while a call waits.
+/// With a quorum of K, `eth_getLogs` is sent to every endpoint that is not
+/// down and only succeeds when at least K of them return the same logs, as
+/// far as the chain is concerned.
+#[derive(Clone, Debug)]
+pub struct FailoverTransport<T> {
+    endpoints: Arc<Vec<Endpoint<T>>>,
+    config: FailoverConfig,
+    ids: Arc<AtomicUsize>,
+    last_health_check: Arc<Mutex<Option<Instant>>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailoverConfig {
+    pub failure_threshold: u32,
+    pub down_for: Duration,
+    pub max_head_lag: u64,
+    pub health_check_interval: Duration,
+    /// How long each endpoint may take to answer a call
+    pub request_timeout: Duration,
+    /// How many endpoints have to agree on the result of `eth_getLogs`,
+    /// `None` to trust the first endpoint that answers
+    pub quorum: Option<usize>,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            down_for: DEFAULT_DOWN_FOR,
+            max_head_lag: DEFAULT_MAX_HEAD_LAG,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            quorum: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint<T> {
+    name: String,
+    transport: T,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    down_until: Option<Instant>,
+    head: Option<U64>,
+}
+
+/// How much an endpoint is preferred, lower is better
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Standing {
+    Healthy,
+    Lagging,
+    Down,
+}
+
+impl<T: Transport> FailoverTransport<T> {
+    /// `endpoints` are named for logging, usually by their URL, and tried in
+    /// the order given
+    pub fn new(
+        endpoints: impl IntoIterator<Item = (String, T)>,
+        config: FailoverConfig,
+    ) -> Result<Self, web3::Error> {
+        let endpoints: Vec<Endpoint<T>> = endpoints
+            .into_iter()
+            .map(|(name, transport)| Endpoint {
+                name,
+                transport,
+                health: Mutex::default(),
+            })
+            .collect();
+
+        if endpoints.is_empty() {
+            return Err(web3::Error::from("no RPC endpoints given".to_string()));
+        }
+        if let Some(quorum) = config.quorum {
+            if quorum == 0 || quorum > endpoints.len() {
+                return Err(web3::Error::from(format!(
+                    "quorum of {} needs between 1 and {} endpoints",
+                    quorum,
+                    endpoints.len()
+                )));
+            }
+        }
+
+        Ok(FailoverTransport {
+            endpoints: Arc::new(endpoints),
+            config,
+            ids: Arc::default(),
+            last_health_check: Arc::default(),
+        })
+    }
+
+    /// Endpoint indices from most to least preferred
+    fn candidates(&self) -> Vec<usize> {
+        self.ranked().into_iter().map(|(_, index)| index).collect()
+    }
+
+    /// Endpoint indices from most to least preferred, with their standing
+    fn ranked(&self) -> Vec<(Standing, usize)> {
+        let now = Instant::now();
+        let best_head = self
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| lock(&endpoint.health).head)
+            .max();
+
+        let mut candidates: Vec<(Standing, usize)> = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, endpoint)| {
+                let health = lock(&endpoint.health);
+                let standing = if health.down_until.is_some_and(|until| until > now) {
+                    Standing::Down
+                } else if matches!(
+                    (health.head, best_head),
+                    (Some(head), Some(best)) if head + U64::from(self.config.max_head_lag) < best
+                ) {
+                    Standing::Lagging
+                } else {
+                    Standing::Healthy
+                };
+                (standing, index)
+            })
+            .collect();
+
+        // The sort is stable, so endpoints keep their configured order
+        // within each standing
+        candidates.sort_by_key(|(standing, _)| *standing);
+        candidates
+    }
+
+    fn record_success(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        let mut health = lock(&endpoint.health);
+        if health.down_until.take().is_some() {
+            log::info!("RPC endpoint {} recovered", endpoint.name);
+        }
+        health.consecutive_failures = 0;
+    }
+
+    fn record_failure(&self, index: usize, err: &web3::Error) {
+        let endpoint = &self.endpoints[index];
+        let mut health = lock(&endpoint.health);
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        log::warn!("RPC endpoint {} failed: {}", endpoint.name, err);
+
+        if health.consecutive_failures >= self.config.failure_threshold {
+            health.down_until = Some(Instant::now() + self.config.down_for);
+            log::error!(
+                "RPC endpoint {} failed {} calls in a row, avoiding it for {:?}",
+                endpoint.name,
+                health.consecutive_failures,
+                self.config.down_for
+            );
+        }
+    }
+
+    /// Whether it is time to compare heads, claiming the check if it is
+    fn health_check_due(&self) -> bool {
+        let mut last = lock(&self.last_health_check);
+        let due = last.is_none_or(|at| at.elapsed() >= self.config.health_check_interval);
+        if due {
+            *last = Some(Instant::now());
+        }
+        due
+    }
+}
+
+impl<T> FailoverTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    /// Send `request` to the endpoint at `index`, failing with a transient
+    /// error if it does not answer within `request_timeout`
+    async fn call(&self, index: usize, id: RequestId, request: Call) -> web3::Result<Value> {
+        let endpoint = &self.endpoints[index];
+        tokio::time::timeout(
+            self.config.request_timeout,
+            endpoint.transport.send(id, request),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(web3::Error::Transport(TransportError::Message(format!(
+                "{} timed out after {:?}",
+                endpoint.name, self.config.request_timeout
+            ))))
+        })
+    }
+
+    /// Ask every endpoint for its head, so lagging ones can be avoided
+    pub async fn check_health(&self) {
+        let requests = (0..self.endpoints.len()).map(|index| {
+            let id = self.ids.fetch_add(1, Ordering::AcqRel);
+            let request = build_request(id, "eth_blockNumber", Vec::new());
+            self.call(index, id, request)
+        });
+        let responses = join_all(requests).await;
+
+        for (index, response) in responses.into_iter().enumerate() {
+            let head = response.and_then(|value| {
+                serde_json::from_value::<U64>(value)
+                    .map_err(|e| web3::Error::Decoder(e.to_string()))
+            });
+            match head {
+                Ok(head) => {
+                    lock(&self.endpoints[index].health).head = Some(head);
+                    self.record_success(index);
+                }
+                Err(err) => self.record_failure(index, &err),
+            }
+        }
+    }
+
+    /// Send to one endpoint after another until one answers
+    async fn send_failover(&self, id: RequestId, request: Call) -> web3::Result<Value> {
+        let mut last_err = None;
+        for index in self.candidates() {
+            match self.call(index, id, request.clone()).await {
+                Ok(value) => {
+                    self.record_success(index);
+                    return Ok(value);
+                }
+                // Only errors of the endpoint itself are worth failing over,
+                // anything else every endpoint would return the same way
+                Err(err) if is_transient_rpc_error(&err) => {
+                    self.record_failure(index, &err);
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(last_err.unwrap_or(web3::Error::Unreachable))
+    }
+
+    /// Send to every endpoint that is not down and accept the result that
+    /// at least `quorum` of them agree on
+    async fn send_quorum(
+        &self,
+        id: RequestId,
+        request: Call,
+        quorum: usize,
+    ) -> web3::Result<Value> {
+        let candidates: Vec<usize> = self
+            .ranked()
+            .into_iter()
+            .filter(|(standing, _)| *standing != Standing::Down)
+            .map(|(_, index)| index)
+            .collect();
+        if candidates.len() < quorum {
+            return Err(web3::Error::Transport(TransportError::Message(format!(
+                "no quorum: only {} of the required {} endpoints are up",
+                candidates.len(),
+                quorum
+            ))));
+        }
+
+        let requests = candidates
+            .iter()
+            .map(|index| self.call(*index, id, request.clone()));
+        let responses = join_all(requests).await;
+
+        // Endpoints vote on the chain data of the logs, clients add fields
+        // of their own that differ between them
+        let mut answers: Vec<(Value, Vec<ChainData>, usize)> = Vec::new();
+        let mut last_err = None;
+        for (index, response) in candidates.into_iter().zip(responses) {
+            let logs = response.and_then(|value| {
+                let logs = serde_json::from_value::<Vec<Log>>(value.clone())
+                    .map_err(|e| web3::Error::InvalidResponse(e.to_string()))?;
+                Ok((value, logs.into_iter().map(ChainData::from).collect()))
+            });
+            match logs {
+                Ok((value, logs)) => {
+                    self.record_success(index);
+                    match answers.iter_mut().find(|(_, answer, _)| *answer == logs) {
+                        Some((_, _, votes)) => *votes += 1,
+                        None => answers.push((value, logs, 1)),
+                    }
+                }
+                Err(err) => {
+                    if is_transient_rpc_error(&err) {
+                        self.record_failure(index, &err);
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        let best = answers.into_iter().max_by_key(|(_, _, votes)| *votes);
+        match best {
+            Some((value, _, votes)) if votes >= quorum => Ok(value),
+            Some((_, _, votes)) => {
+                log::warn!(
+                    "RPC endpoints disagree: only {} of the required {} returned the same result",
+                    votes,
+                    quorum
+                );
+                Err(web3::Error::InvalidResponse(format!(
+                    "no quorum: only {} of the required {} endpoints agreed",
+                    votes, quorum
+                )))
+            }
+            None => Err(last_err.unwrap_or(web3::Error::Unreachable)),
+        }
+    }
+}
+
+/// The fields of a log that are part of the chain, which every endpoint
+/// returns the same
+#[derive(Debug, PartialEq, Eq)]
+struct ChainData {
+    address: H160,
+    topics: Vec<H256>,
+    data: Bytes,
+    block_hash: Option<H256>,
+    block_number: Option<U64>,
+    transaction_hash: Option<H256>,
+    log_index: Option<U256>,
+}
+
+impl From<Log> for ChainData {
+    fn from(log: Log) -> Self {
+        ChainData {
+            address: log.address,
+            topics: log.topics,
+            data: log.data,
+            block_hash: log.block_hash,
+            block_number: log.block_number,
+            transaction_hash: log.transaction_hash,
+            log_index: log.log_index,
+        }
+    }
+}
+
+impl<T> Transport for FailoverTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        let id = self.ids.fetch_add(1, Ordering::AcqRel);
+        (id, build_request(id, method, params))
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        let transport = self.clone();
+        Box::pin(async move {
+            if transport.health_check_due() {
+                let checker = transport.clone();
+                tokio::spawn(async move { checker.check_health().await });
+            }
+            let is_get_logs = matches!(
+                &request,
+                Call::MethodCall(call) if call.method == "eth_getLogs"
+            );
+            match transport.config.quorum {
+                Some(quorum) if is_get_logs => transport.send_quorum(id, request, quorum).await,
+                _ => transport.send_failover(id, request).await,
+            }
+        })
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::MockTransport;
+
+    /// An endpoint at head `head` that answers `eth_getLogs` with `logs`
+    /// and anything else with its name
+    fn endpoint(name: &'static str, head: u64, logs: Value) -> (String, MockTransport) {
+        let transport = MockTransport::new(move |method, _| match method {
+            "eth_blockNumber" => Ok(json!(format!("{:#x}", head))),
+            "eth_getLogs" => Ok(logs.clone()),
+            _ => Ok(json!(name)),
+        });
+        (name.to_string(), transport)
+    }
+
+    fn failing(name: &'static str) -> (String, MockTransport) {
+        let transport = MockTransport::new(|_, _| Err(web3::Error::Unreachable));
+        (name.to_string(), transport)
+    }
+
+    fn config() -> FailoverConfig {
+        FailoverConfig {
+            // Heads are only compared when a test asks for it
+            health_check_interval: Duration::from_secs(3600),
+            ..Default::default()
+        }
+    }
+
+    fn failover(
+        endpoints: Vec<(String, MockTransport)>,
+        config: FailoverConfig,
+    ) -> (FailoverTransport<MockTransport>, Vec<MockTransport>) {
+        let transports = endpoints.iter().map(|(_, t)| t.clone()).collect();
+        (
+            FailoverTransport::new(endpoints, config).unwrap(),
+            transports,
+        )
+    }
+
+    /// A log as a client returns it, with `extra` fields of its own
+    fn log(data: &str, extra: Value) -> Value {
+        let mut log = json!({
+            "address": "0x1111111111111111111111111111111111111111",
+            "topics": ["0x2222222222222222222222222222222222222222222222222222222222222222"],
+            "data": data,
+            "blockHash": "0x3333333333333333333333333333333333333333333333333333333333333333",
+            "blockNumber": "0x10",
+            "transactionHash": "0x4444444444444444444444444444444444444444444444444444444444444444",
+            "transactionIndex": "0x0",
+            "logIndex": "0x1",
+        });
+        log.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        json!([log])
+    }
+
+    #[tokio::test]
+    async fn fails_over_in_the_configured_order() {
+        let (transport, endpoints) = failover(
+            vec![
+                failing("a"),
+                endpoint("b", 1, json!([])),
+                endpoint("c", 1, json!([])),
+            ],
+            config(),
+        );
+
+        let answer = transport.execute("eth_chainId", Vec::new()).await.unwrap();
+        assert_eq!(answer, json!("b"));
+        assert_eq!(endpoints[0].calls("eth_chainId"), 1);
+        assert_eq!(endpoints[2].calls("eth_chainId"), 0);
+    }
+
+    #[tokio::test]
+    async fn tries_down_endpoints_last() {
+        let config = FailoverConfig {
+            failure_threshold: 1,
+            ..config()
+        };
+        let (transport, endpoints) =
+            failover(vec![failing("a"), endpoint("b", 1, json!([]))], config);
+
+        transport.execute("eth_chainId", Vec::new()).await.unwrap();
+        assert_eq!(transport.candidates(), vec![1, 0]);
+
+        transport.execute("eth_chainId", Vec::new()).await.unwrap();
+        assert_eq!(endpoints[0].calls("eth_chainId"), 1);
+    }
+
+    #[tokio::test]
+    async fn ranks_lagging_endpoints_after_healthy_ones() {
+        let (transport, _) = failover(
+            vec![
+                endpoint("a", 100, json!([])),
+                endpoint("b", 200, json!([])),
+                endpoint("c", 198, json!([])),
+            ],
+            config(),
+        );
+
+        transport.check_health().await;
+        assert_eq!(
+            transport.ranked(),
+            vec![
+                (Standing::Healthy, 1),
+                (Standing::Healthy, 2),
+                (Standing::Lagging, 0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_when_an_endpoint_times_out() {
+        let config = FailoverConfig {
+            request_timeout: Duration::from_millis(50),
+            ..config()
+        };
+        let (slow, transport) = endpoint("slow", 1, json!([]));
+        let (transport, _) = failover(
+            vec![
+                (slow, transport.with_delay(Duration::from_secs(10))),
+                endpoint("b", 1, json!([])),
+            ],
+            config,
+        );
+
+        let answer = transport.execute("eth_chainId", Vec::new()).await.unwrap();
+        assert_eq!(answer, json!("b"));
+    }
+
+    #[tokio::test]
+    async fn reaches_a_quorum_on_chain_data_only() {
+        let config = FailoverConfig {
+            quorum: Some(3),
+            ..config()
+        };
+        let (transport, _) = failover(
+            vec![
+                endpoint("a", 1, log("0x01", json!({ "removed": false }))),
+                endpoint(
+                    "b",
+                    1,
+                    log(
+                        "0x01",
+                        json!({ "transactionLogIndex": "0x0", "logType": null }),
+                    ),
+                ),
+                endpoint("c", 1, log("0x01", json!({ "blockTimestamp": "0x5" }))),
+            ],
+            config,
+        );
+
+        let answer = transport
+            .execute("eth_getLogs", vec![json!({})])
+            .await
+            .unwrap();
+        assert_eq!(answer, log("0x01", json!({ "removed": false })));
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_quorum() {
+        let config = FailoverConfig {
+            quorum: Some(2),
+            ..config()
+        };
+        let (transport, _) = failover(
+            vec![
+                endpoint("a", 1, log("0x01", json!({}))),
+                endpoint("b", 1, log("0x02", json!({}))),
+                failing("c"),
+            ],
+            config,
+        );
+
+        let err = transport
+            .execute("eth_getLogs", vec![json!({})])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no quorum"), "{}", err);
+    }
+}
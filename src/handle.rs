@@ -0,0 +1,61 @@
+use tokio::sync::oneshot::Sender;
+use tokio::task::JoinHandle;
+
+use crate::{Acknowledger, EoServerError, StopToken};
+
+/// An `EoServer` running on its own task, returned by `EoServer::spawn`.
+///
+/// Dropping the handle leaves the server running until its event receiver
+/// is dropped.
+#[derive(Debug)]
+pub struct EoServerHandle {
+    task: JoinHandle<Result<(), EoServerError>>,
+    stop: Option<Sender<StopToken>>,
+    acks: Acknowledger,
+}
+
+impl EoServerHandle {
+    pub(crate) fn new(
+        task: JoinHandle<Result<(), EoServerError>>,
+        stop: Sender<StopToken>,
+        acks: Acknowledger,
+    ) -> Self {
+        EoServerHandle {
+            task,
+            stop: Some(stop),
+            acks,
+        }
+    }
+
+    /// Acknowledges the events received from the server, see
+    /// `DeliveryGuarantee`
+    pub fn acknowledger(&self) -> Acknowledger {
+        self.acks.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Ask the server to stop. It finishes delivering the batch it is working
+    /// on and saves its checkpoint first, so keep receiving and acknowledging
+    /// events until the receiver returns `None`.
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(StopToken);
+        }
+    }
+
+    /// Stop the server immediately, wherever it is, without saving its
+    /// checkpoint
+    pub fn abort(&self) {
+        self.task.abort()
+    }
+
+    /// Wait for the server to stop
+    pub async fn join(self) -> Result<(), EoServerError> {
+        self.task
+            .await
+            .map_err(|err| EoServerError::Other(format!("listener task failed: {}", err)))?
+    }
+}
@@ -5,9 +5,8 @@ use std::time::Duration;
 
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Keccak256};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::{mpsc::UnboundedSender, oneshot::Receiver};
+use tokio::sync::oneshot::Receiver;
+use tokio::sync::{mpsc, oneshot};
 use web3::types::U64;
 use web3::{
     contract::{
@@ -15,49 +14,82 @@ use web3::{
         Contract, Options,
     },
     ethabi::RawLog as ParsedLog,
-    transports::Http,
     types::{Address, BlockId, BlockNumber, Filter, FilterBuilder, Log, H160, H256, U256},
     Error as Web3Error, Transport, Web3,
 };
 
-#[macro_export]
-macro_rules! log_handler {
-    () => {
-        |logs| match logs {
-            Ok(l) => l,
-            Err(_) => Vec::new(),
-        }
-    };
-}
-pub fn get_abi() -> Result<web3::ethabi::Contract, EoServerError> {
-    web3::ethabi::Contract::load(&include_bytes!("../eo_contract_abi.json")[..])
-        .map_err(|e| EoServerError::Other(e.to_string()))
-}
+pub mod backfill;
+pub mod checkpoint;
+pub mod confirmation;
+pub mod dedup;
+pub mod delivery;
+mod error;
+pub mod events;
+pub mod failover;
+pub mod handle;
+#[cfg(test)]
+mod mock;
+pub mod poll;
+pub mod range;
+pub mod registry;
+pub mod reorg;
+pub mod rpc;
+mod shutdown;
+pub mod start;
+pub mod store;
+pub mod subscription;
+
+pub use backfill::{BackfillConfig, DEFAULT_BACKFILL_CONCURRENCY};
+pub use checkpoint::{BlocksProcessed, Checkpoint, EventCheckpoint};
+pub use confirmation::Confirmation;
+pub use dedup::DeliveredEvents;
+pub use delivery::{
+    Acknowledger, DeliveryGuarantee, DEFAULT_ACK_TIMEOUT, DEFAULT_CHECKPOINT_INTERVAL,
+};
+pub use error::EoServerError;
+pub use events::{BlobIndexSettledEvent, BridgeEvent, ContractEvent, EoEvent, EventId};
+pub use failover::{FailoverConfig, FailoverTransport};
+pub use handle::EoServerHandle;
+pub use poll::DEFAULT_MAX_POLL_INTERVAL;
+pub use range::{
+    is_provider_limit_error, merged_range, plan_scan, BlockRanges, RangeScheduler, ScanPlan,
+};
+pub use registry::{
+    event_signature, signature_topic, EventHandler, EventRegistry, RegisteredEvent,
+};
+pub use reorg::DEFAULT_REORG_DEPTH;
+pub use rpc::{CircuitBreaker, RetryPolicy};
+pub use start::StartBlock;
+#[cfg(feature = "sqlite")]
+pub use store::SqliteCheckpointStore;
+pub use store::{CheckpointStore, FileCheckpointStore, MemoryCheckpointStore};
+pub use subscription::Reconnect;
 
-pub fn get_blob_index_settled_topic() -> Option<Vec<H256>> {
-    let mut hasher = Keccak256::new();
-    let blob_index_settled_sig = b"BlobIndexSettled(address[],bytes32,uint128,uint256)";
-    hasher.update(blob_index_settled_sig);
-    let res: [u8; 32] = hasher.finalize().try_into().ok()?;
-    let blob_settled_topic = H256::from(res);
-    Some(vec![blob_settled_topic])
-}
+use shutdown::Shutdown;
 
-pub fn get_bridge_event_topic() -> Option<Vec<H256>> {
-    let mut hasher = Keccak256::new();
-    let bridge_sig = b"Bridge(address,address,uint256,uint256,string,uint256)";
-    hasher.update(bridge_sig);
-    let res: [u8; 32] = hasher.finalize().try_into().ok()?;
-    let bridge_topic = H256::from(res);
-    Some(vec![bridge_topic])
+/// How many decoded events `spawn` buffers before the listener waits for the
+/// consumer to catch up
+pub const DEFAULT_EVENT_BUFFER: usize = 1_024;
+
+/// The Executable Oracle ABI the listener was built with
+pub fn get_abi() -> Result<web3::ethabi::Contract, EoServerError> {
+    web3::ethabi::Contract::load(&include_bytes!("../eo_contract_abi.json")[..])
+        .map_err(|e| EoServerError::abi("failed to parse the Executable Oracle ABI", e))
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
-pub struct BlocksProcessed {
-    pub bridge: Option<U64>,
-    pub settle: Option<U64>,
-    pub bridge_processed: BTreeSet<U64>,
-    pub settled_processed: BTreeSet<U64>,
+/// Load a contract ABI from a JSON file, so an upgraded contract does not
+/// need a new build
+pub fn load_abi(
+    path: impl AsRef<std::path::Path>,
+) -> Result<web3::ethabi::Contract, EoServerError> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path).map_err(|e| EoServerError::Abi {
+        reason: format!("failed to open {}: {}", path.display(), e),
+        source: None,
+    })?;
+
+    web3::ethabi::Contract::load(std::io::BufReader::new(file))
+        .map_err(|e| EoServerError::abi(format!("failed to parse {}", path.display()), e))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -80,50 +112,53 @@ pub enum Event {
     },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum EventType {
-    Bridge(web3::ethabi::Event),
-    Settlement(web3::ethabi::Event),
-}
-
-#[derive(Clone, Debug)]
+/// Everything one tick of `EoServer::next` found, across all event types
+#[derive(Clone, Debug, Default)]
 pub struct EventLogResult {
-    pub event_type: EventType,
-    pub log_result: web3::Result<Vec<web3::ethabi::Log>>,
+    /// Decoded events of every event type in (block_number, log_index)
+    /// order, preceded by an `EoEvent::Removed` for every earlier event that
+    /// a chain reorganization retracted
+    pub events: Vec<EoEvent>,
+    /// Why the logs could not be fetched. Their blocks stay unscanned and
+    /// are fetched again on the next tick.
+    pub error: Option<EoServerError>,
+    /// Logs in this batch that could not be decoded, one
+    /// `EoServerError::Decode` per offending log. The blocks their event
+    /// had to scan stay unscanned, so the same logs fail again on the next
+    /// tick.
+    pub decode_errors: Vec<EoServerError>,
 }
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
-pub struct StopToken;
-
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
-pub enum EoServerError {
-    Other(String),
-}
-
-impl From<EoServerBuilderError> for EoServerError {
-    fn from(value: EoServerBuilderError) -> Self {
-        Self::Other(value.to_string())
-    }
-}
-
-impl std::fmt::Display for EoServerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-
-impl std::error::Error for EoServerError {
-    fn description(&self) -> &str {
-        match self {
-            EoServerError::Other(desc) => &desc[..],
+/// Events decoded from a batch of logs alongside the errors for any logs
+/// that failed to decode
+pub type DecodedLogs = (Vec<EoEvent>, Vec<EoServerError>);
+
+impl EventLogResult {
+    fn new(processed: Result<DecodedLogs, EoServerError>) -> Self {
+        match processed {
+            Ok((events, decode_errors)) => EventLogResult {
+                events,
+                error: None,
+                decode_errors,
+            },
+            Err(err) => EventLogResult {
+                error: Some(err),
+                ..Default::default()
+            },
         }
     }
 }
 
+/// Sent to a running `EoServer` to stop it once the batch it is working on
+/// has been delivered and its checkpoint saved
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct StopToken;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ContractAddress([u8; 32]);
 
 impl From<String> for ContractAddress {
+    #[allow(clippy::sliced_string_as_bytes)]
     fn from(value: String) -> Self {
         let arr = value[..64].as_bytes();
         let mut bytes = [0u8; 32];
@@ -136,331 +171,472 @@ impl From<String> for ContractAddress {
 }
 
 /// An ExecutableOracle server that listens for events emitted from
-/// Smart Contracts
+/// Smart Contracts over any `web3` transport (HTTP, WebSocket or IPC)
 #[derive(Builder, Debug, Clone)]
-pub struct EoServer {
-    web3: Web3<Http>,
+pub struct EoServer<T: Transport> {
+    web3: Web3<T>,
     eo_address: EoAddress,
+    /// The expected time between blocks, until it has been measured from
+    /// the timestamps of head blocks
     block_time: Duration,
-    bridge_processed_blocks: BTreeSet<U64>,
-    settled_processed_blocks: BTreeSet<U64>,
-    contract: web3::contract::Contract<Http>,
-    bridge_topic: Option<Vec<H256>>,
-    blob_settled_topic: Option<Vec<H256>>,
-    blob_settled_filter: Filter,
-    bridge_filter: Filter,
-    current_bridge_filter_block: U64,
-    current_blob_settlement_filter_block: U64,
-    blob_settled_event: web3::ethabi::Event,
-    bridge_event: web3::ethabi::Event,
-    path: std::path::PathBuf,
+    /// The longest the poll loop waits while the head does not move
+    #[builder(default = "DEFAULT_MAX_POLL_INTERVAL")]
+    max_poll_interval: Duration,
+    #[builder(setter(skip))]
+    poll: poll::PollState,
+    contract: web3::contract::Contract<T>,
+    /// The events to listen for, each with its own topic, confirmation
+    /// depth, scan progress and handler
+    registry: EventRegistry,
+    /// Where progress is checkpointed, set with `checkpoint_store` or with
+    /// `path` for a checkpoint file
+    #[builder(setter(custom))]
+    checkpoint_store: std::sync::Arc<dyn CheckpointStore>,
+    /// Whether the checkpoint waits for events to be acknowledged
+    #[builder(default)]
+    delivery: DeliveryGuarantee,
+    /// How often the checkpoint is saved between batches of events
+    #[builder(default = "DEFAULT_CHECKPOINT_INTERVAL")]
+    checkpoint_interval: Duration,
+    /// How long a stopping server waits for delivered events to be
+    /// acknowledged before saving its final checkpoint
+    #[builder(default = "DEFAULT_ACK_TIMEOUT")]
+    ack_timeout: Duration,
+    #[builder(setter(skip))]
+    checkpoints: delivery::CheckpointTracker,
+    /// How failed RPC calls are retried
+    #[builder(default)]
+    retry_policy: RetryPolicy,
+    /// Pauses the listener while the provider keeps failing
+    #[builder(default)]
+    circuit_breaker: CircuitBreaker,
+    /// The RPC endpoint `web3` was connected to, used to reconnect the
+    /// transport when a subscription drops
+    #[builder(default)]
+    endpoint: Option<String>,
+    #[builder(setter(skip))]
+    subscription_cursor: Option<(U64, U256)>,
+    /// Subscribed logs waiting for their confirmation depth
+    #[builder(setter(skip))]
+    held_logs: BTreeMap<(U64, U256), Log>,
+    /// Catch up on history with concurrent `eth_getLogs` calls before live
+    /// tailing starts, `None` to catch up one range per poll
+    #[builder(default)]
+    backfill: Option<BackfillConfig>,
+    /// Where events without a checkpoint start scanning, `None` to keep the
+    /// start blocks the schedulers were configured with
+    #[builder(default)]
+    start_block: Option<StartBlock>,
+    /// How many blocks of history are kept to detect and roll back reorgs
+    #[builder(default = "DEFAULT_REORG_DEPTH")]
+    reorg_depth: u64,
+    /// Hashes of the last `reorg_depth` blocks we saw, to detect reorgs
+    #[builder(setter(skip))]
+    block_hashes: BTreeMap<U64, H256>,
+    /// Events delivered from the last `reorg_depth` blocks, to retract them
+    /// when their block is reorged out
+    #[builder(setter(skip))]
+    recent_events: BTreeMap<U64, Vec<EoEvent>>,
+    /// Events delivered from the last `reorg_depth` blocks, to skip logs
+    /// that are decoded again
+    #[builder(setter(skip))]
+    delivered: DeliveredEvents,
+    /// Retractions found by reorg detection that have not been returned
+    /// from `next` yet
+    #[builder(setter(skip))]
+    pending_removed: Vec<EoEvent>,
 }
 
-impl EoServer {
-    pub async fn load_processed_blocks(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buf = Vec::new();
-        let mut file = std::fs::OpenOptions::new().read(true).open(&self.path)?;
-
-        file.read_to_end(&mut buf)?;
+impl<T: Transport> EoServer<T> {
+    /// Resume from the saved checkpoint. Every event restarts at the block
+    /// its scan started at, events without a checkpoint start at the
+    /// configured start block instead.
+    pub async fn load_processed_blocks(&mut self) -> Result<(), EoServerError> {
+        let checkpoint = self.checkpoint_store.load()?;
+        let saved = |name: &str| checkpoint.as_ref().and_then(|c| c.event(name));
+
+        // Only resolved when needed, it may take RPC calls
+        let start_block = match self.start_block {
+            Some(start)
+                if self
+                    .registry
+                    .iter()
+                    .any(|event| saved(event.name()).is_none()) =>
+            {
+                let block = self.resolve_start_block(start).await?;
+                Some((start, block))
+            }
+            _ => None,
+        };
 
-        let blocks_processed: BlocksProcessed = bincode::deserialize(&buf)?;
-        if let Some(b) = blocks_processed.bridge {
-            self.current_bridge_filter_block = b;
+        for event in self.registry.iter_mut() {
+            match saved(event.name()) {
+                Some(saved) => {
+                    let scheduler = event.scheduler_mut();
+                    scheduler.start_at(saved.start_block);
+                    scheduler.restore(&saved.scanned);
+                }
+                None => {
+                    if let Some((start, block)) = start_block {
+                        log::info!(
+                            "no checkpoint found for {}, starting {:?} at block {}",
+                            event.name(),
+                            start,
+                            block
+                        );
+                        event.scheduler_mut().start_at(block);
+                    }
+                }
+            }
         }
 
-        if let Some(b) = blocks_processed.settle {
-            self.current_blob_settlement_filter_block = b;
+        if let Some(checkpoint) = checkpoint {
+            self.delivered = checkpoint.delivered;
+            self.block_hashes = checkpoint.block_hashes;
+            self.recent_events = checkpoint.recent_events;
         }
 
-        self.bridge_processed_blocks = blocks_processed.bridge_processed;
-        self.settled_processed_blocks = blocks_processed.settled_processed;
-
         Ok(())
     }
 
-    pub async fn run(mut self) -> Result<(), web3::Error> {
-        self.run_loop().await
+    /// Poll for events once per block and send them to `events` in chain
+    /// order, until a `StopToken` arrives on `stop` or the receiver is
+    /// dropped. The checkpoint is saved before returning.
+    pub async fn run(
+        mut self,
+        events: mpsc::Sender<EoEvent>,
+        stop: Receiver<StopToken>,
+    ) -> Result<(), EoServerError> {
+        let mut shutdown = Shutdown::new(stop);
+        if !self.backfill(&events, &mut shutdown).await? {
+            self.run_loop(&events, &mut shutdown).await?;
+        }
+        self.flush_checkpoint(&events).await
     }
 
     pub async fn next(&mut self) -> EventLogResult {
-        let log_handler = log_handler!();
-        tokio::select!(
-            blob_settled_logs = self.web3.eth().logs(
-                self.blob_settled_filter.clone()
-            ) => {
-                let log_result = self.process_logs(
-                    EventType::Settlement(
-                        self.blob_settled_event.clone()
-                    ), blob_settled_logs,
-                    log_handler,
-                ).await.map_err(|e| Web3Error::from(e.to_string()));
-
-                return EventLogResult {
-                    event_type: EventType::Settlement(self.blob_settled_event.clone()),
-                    log_result
-                };
-            },
-            bridge_logs = self.web3.eth().logs(
-                self.bridge_filter.clone()
-            ) => {
-                let log_result = self.process_logs(
-                    EventType::Bridge(
-                        self.bridge_event.clone()
-                    ), bridge_logs,
-                    log_handler,
-                ).await.map_err(|e| Web3Error::from(e.to_string()));
-                return EventLogResult {
-                    event_type: EventType::Bridge(self.bridge_event.clone()),
-                    log_result
-                };
-            },
-        )
-    }
-
-    async fn run_loop(&mut self) -> Result<(), web3::Error> {
-        let blob_settled_event = self
-            .contract
-            .abi()
-            .event("BlobIndexSettled")
-            .map_err(|e| Web3Error::from(e.to_string()))?
-            .clone();
-
-        let bridge_event = self
-            .contract
-            .abi()
-            .event("Bridge")
-            .map_err(|e| Web3Error::from(e.to_string()))?
-            .clone();
-
-        loop {
-            let log_handler = log_handler!();
-            tokio::select!(
-                blob_settled_logs = self.web3.eth().logs(self.blob_settled_filter.clone()) => {
-                    self.process_logs(EventType::Settlement(blob_settled_event.clone()), blob_settled_logs, log_handler).await;
-                },
-                bridge_logs = self.web3.eth().logs(self.bridge_filter.clone()) => {
-                    self.process_logs(
-                        EventType::Bridge(
-                            bridge_event.clone()
-                        ), bridge_logs, log_handler
-                    ).await;
-                },
-            );
-            tokio::time::sleep(self.block_time).await;
+        match self.check_reorg().await {
+            Ok(removed) => self.pending_removed.extend(removed),
+            Err(err) => log::warn!("failed to check for chain reorganization: {}", err),
         }
-        Ok(())
+
+        let mut result = EventLogResult::new(self.scan().await);
+        result
+            .events
+            .sort_by_key(|event| (event.block_number(), event.log_index()));
+        result
+            .events
+            .splice(0..0, std::mem::take(&mut self.pending_removed));
+
+        result
     }
 
-    async fn process_logs<F>(
+    async fn run_loop(
         &mut self,
-        event_type: EventType,
-        logs: Result<Vec<Log>, Web3Error>,
-        handler: F,
-    ) -> Result<Vec<web3::ethabi::Log>, EoServerError>
-    where
-        F: FnOnce(Result<Vec<Log>, Web3Error>) -> Vec<Log>,
-    {
-        let block_number = self.web3.eth().block_number().await.map_err(|e| {
-            EoServerError::Other(format!("Error attempting to get block number: {}", e))
-        })?;
-
-        let events = handler(logs);
-        match event_type {
-            EventType::Bridge(event_abi) => {
-                let bridge_log = self.handle_bridge_event(events, &event_abi);
-                if let Ok(logs) = &bridge_log {
-                    if logs.len() > 0 {
-                        log::info!("discovered logs: logs.len() = {}", logs.len());
-                        self.increment_bridge_filter(block_number, true);
-                    } else {
-                        self.increment_bridge_filter(block_number, false);
-                    }
-                }
-                return bridge_log;
+        events: &mpsc::Sender<EoEvent>,
+        shutdown: &mut Shutdown,
+    ) -> Result<(), EoServerError> {
+        // A stop request never interrupts `next`, so a batch that has been
+        // marked as scanned is always delivered before we stop
+        while !shutdown.is_stopped() {
+            if self.wait_for_circuit(shutdown).await {
+                break;
             }
-            EventType::Settlement(event_abi) => {
-                let blob_log = self.handle_settlement_event(events, &event_abi);
-                if let Ok(logs) = &blob_log {
-                    if logs.len() > 0 {
-                        self.increment_blob_filter(block_number, true);
-                    } else {
-                        self.increment_blob_filter(block_number, false);
-                    }
-                }
-                return blob_log;
+
+            let result = self.next().await;
+            if let Some(err) = &result.error {
+                log::error!("{}", err);
+            }
+            // What was decoded is delivered first, its blocks have been
+            // marked as scanned
+            let fatal = result
+                .error
+                .into_iter()
+                .chain(result.decode_errors)
+                .find(|err| !err.is_transient());
+            if self.deliver_batch(events, result.events).await? {
+                log::info!("event receiver dropped, stopping");
+                return Ok(());
+            }
+            if let Some(err) = fatal {
+                log::error!("stopping on an error that retrying cannot fix");
+                return Err(err);
+            }
+
+            let delay = self.poll_delay();
+            if !delay.is_zero() && shutdown.sleep(delay).await {
+                break;
             }
         }
 
-        Ok(vec![])
+        log::info!("stop requested, stopping");
+        Ok(())
     }
 
-    fn handle_bridge_event(
+    /// Save the checkpoint before stopping, so a restart resumes exactly
+    /// where we stopped
+    async fn flush_checkpoint(
         &mut self,
-        events: Vec<Log>,
-        event_abi: &web3::ethabi::Event,
-    ) -> Result<Vec<web3::ethabi::Log>, EoServerError> {
-        let mut parsed_logs = Vec::new();
-        let mut blocks_processed = Vec::new();
-        for event in events {
-            let block_number = event
-                .block_number
-                .ok_or(EoServerError::Other("Log missing block number".to_string()))?;
-            let log = self.parse_bridge_event(event, event_abi)?;
-            parsed_logs.push(log);
-            blocks_processed.push(block_number);
-        }
-        self.bridge_processed_blocks.extend(blocks_processed);
-        Ok(parsed_logs)
+        events: &mpsc::Sender<EoEvent>,
+    ) -> Result<(), EoServerError> {
+        self.wait_for_acks(events).await;
+        self.commit().await?;
+        log::info!("checkpoint saved");
+        Ok(())
     }
 
-    fn handle_settlement_event(
-        &mut self,
-        events: Vec<Log>,
-        event_abi: &web3::ethabi::Event,
-    ) -> Result<Vec<web3::ethabi::Log>, EoServerError> {
-        let mut parsed_logs = Vec::new();
-        let mut blocks_processed = Vec::new();
-        for event in events {
-            let block_number = event
-                .block_number
-                .ok_or(EoServerError::Other("Log missing block number".to_string()))?;
-            let log = self.parse_settlement_event(event, event_abi)?;
-            parsed_logs.push(log);
-            blocks_processed.push(block_number);
-        }
-        self.settled_processed_blocks.extend(blocks_processed);
-        Ok(parsed_logs)
-    }
+    /// Fetch the logs of every event type with a single `eth_getLogs` call
+    /// over the next block range any of them still has to scan
+    async fn scan(&mut self) -> Result<DecodedLogs, EoServerError> {
+        let head = self.head().await?;
+        let confirmed = self.confirmed_blocks(head).await?;
 
-    fn parse_bridge_event(
-        &self,
-        event: Log,
-        event_abi: &web3::ethabi::Event,
-    ) -> Result<web3::ethabi::Log, EoServerError> {
-        let parsed_log = event_abi
-            .parse_log(web3::ethabi::RawLog {
-                topics: event.topics.clone(),
-                data: event.data.0.clone(),
-            })
-            .map_err(|e| EoServerError::Other(e.to_string()))?;
-        Ok(parsed_log)
-    }
-
-    fn parse_settlement_event(
-        &self,
-        event: Log,
-        event_abi: &web3::ethabi::Event,
-    ) -> Result<web3::ethabi::Log, EoServerError> {
-        let parsed_log = event_abi
-            .parse_log(web3::ethabi::RawLog {
-                topics: event.topics.clone(),
-                data: event.data.0.clone(),
-            })
-            .map_err(|e| EoServerError::Other(e.to_string()))?;
+        let end = confirmed.iter().copied().max().unwrap_or_default();
+        self.poll.behind = false;
 
-        Ok(parsed_log)
-    }
+        let schedulers = self.registry.iter().map(RegisteredEvent::scheduler);
+        let Some(plan) = plan_scan(schedulers.zip(confirmed)) else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let (from_block, to_block) = plan.range;
 
-    fn increment_bridge_filter(
-        &mut self,
-        block_number: U64,
-        log_discovered: bool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let contract_address = self
-            .eo_address
-            .clone()
-            .parse()
-            .map_err(|err| EoServerError::Other(err.to_string()))?;
-
-        let default: U64 = U64::from(0);
-        let from_block = self
-            .bridge_processed_blocks
-            .last()
-            .unwrap_or_else(|| &default);
-        let to_block = self.current_bridge_filter_block + U64::from(1);
         log::info!(
             "filtering from block {} to block {}",
             &from_block,
             &to_block
         );
 
-        let new_filter = FilterBuilder::default()
-            .from_block(BlockNumber::Number(*from_block)) // Last processed block
-            .to_block(BlockNumber::Number(to_block))
-            .address(vec![contract_address])
-            .topics(self.bridge_topic.clone(), None, None, None)
-            .build();
-
-        if log_discovered {
-            self.bridge_processed_blocks.insert(block_number);
-        }
+        let contract_address = self.eo_address.parse()?;
+        let logs = self
+            .fetch_logs(contract_address, from_block, to_block)
+            .await;
+        let chunk_sizes = self.chunk_sizes();
+        let result = self.process_logs(plan, logs);
+
+        // Poll again right away while there is more to scan, or to retry a
+        // range the provider rejected in smaller chunks. Once the chunks
+        // cannot shrink any further, retrying right away would fail again.
+        self.poll.behind = match &result {
+            Ok(_) => to_block < end,
+            Err(EoServerError::ProviderLimit { .. }) => self.chunk_sizes() != chunk_sizes,
+            Err(_) => false,
+        };
+        result
+    }
 
-        self.current_bridge_filter_block = block_number;
-        self.bridge_filter = new_filter;
+    /// The chunk size of every registered event, in registration order
+    pub(crate) fn chunk_sizes(&self) -> Vec<u64> {
+        self.registry
+            .iter()
+            .map(|event| event.scheduler().chunk_size())
+            .collect()
+    }
 
-        Ok(())
+    /// Halve the chunk size of every registered event after the provider
+    /// rejected a range. Returns the smallest chunk size, or `None` if every
+    /// event was already at its minimum.
+    pub(crate) fn shrink_chunks(&mut self) -> Option<u64> {
+        let chunk_sizes = self.chunk_sizes();
+        let chunk_size = self
+            .registry
+            .iter_mut()
+            .map(|event| event.scheduler_mut().shrink())
+            .min()
+            .unwrap_or_default();
+        (self.chunk_sizes() != chunk_sizes).then_some(chunk_size)
     }
 
-    fn increment_blob_filter(
-        &mut self,
-        block_number: U64,
-        log_discovered: bool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let contract_address = self
-            .eo_address
-            .clone()
-            .parse()
-            .map_err(|err| EoServerError::Other(err.to_string()))?;
-
-        let default: U64 = U64::from(0);
-        let from_block = self
-            .settled_processed_blocks
-            .last()
-            .unwrap_or_else(|| &default);
-        let to_block = self.current_blob_settlement_filter_block + U64::from(1);
-
-        let new_filter = FilterBuilder::default()
-            .from_block(BlockNumber::Number(*from_block))
-            .to_block(BlockNumber::Number(to_block))
-            .address(vec![contract_address])
-            .topics(self.blob_settled_topic.clone(), None, None, None)
-            .build();
-
-        if log_discovered {
-            self.settled_processed_blocks.insert(block_number);
-        }
-        self.current_blob_settlement_filter_block = block_number;
-        self.blob_settled_filter = new_filter;
+    /// Fetch the logs of every registered event in `from_block..=to_block`
+    /// in chain order, with one `eth_getLogs` call per set of events that
+    /// share the same indexed filters
+    pub(crate) async fn fetch_logs(
+        &self,
+        address: H160,
+        from_block: U64,
+        to_block: U64,
+    ) -> Result<Vec<Log>, Web3Error> {
+        let filters: Vec<Filter> = self
+            .registry
+            .log_topics()
+            .into_iter()
+            .map(|[topic0, topic1, topic2, topic3]| {
+                FilterBuilder::default()
+                    .from_block(BlockNumber::Number(from_block))
+                    .to_block(BlockNumber::Number(to_block))
+                    .address(vec![address])
+                    .topics(topic0, topic1, topic2, topic3)
+                    .build()
+            })
+            .collect();
 
-        Ok(())
-    }
+        let batches = futures::future::try_join_all(
+            filters
+                .iter()
+                .map(|filter| self.rpc("eth_getLogs", || self.web3.eth().logs(filter.clone()))),
+        )
+        .await?;
 
-    fn inner_highest_bridge_block_processed(&self) -> Option<&U64> {
-        self.bridge_processed_blocks.last()
+        let mut logs: Vec<Log> = batches.into_iter().flatten().collect();
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+        Ok(logs)
     }
 
-    fn inner_highest_bridge_block_processed_owned(&self) -> Option<U64> {
-        if let Some(b) = self.bridge_processed_blocks.last() {
-            return Some(b.clone());
+    /// Split the logs fetched for the planned range by event, decode the
+    /// ones inside the portion of the range each event still had to scan and
+    /// mark those portions as scanned. A portion with a log that cannot be
+    /// decoded is left unscanned and its decode errors are returned. A
+    /// failed fetch leaves the range to be retried, shrinking the chunk size
+    /// if the provider said the range was too large.
+    fn process_logs(
+        &mut self,
+        plan: ScanPlan,
+        logs: Result<Vec<Log>, Web3Error>,
+    ) -> Result<DecodedLogs, EoServerError> {
+        let ScanPlan {
+            range: (from_block, to_block),
+            portions,
+        } = plan;
+        let logs = match logs {
+            Ok(logs) => logs,
+            Err(err) if is_provider_limit_error(&err) => {
+                match self.shrink_chunks() {
+                    Some(chunk_size) => log::warn!(
+                        "provider rejected blocks {} to {}, retrying in chunks of {} blocks",
+                        from_block,
+                        to_block,
+                        chunk_size
+                    ),
+                    None => log::warn!(
+                        "provider rejected blocks {} to {} at the smallest chunk size",
+                        from_block,
+                        to_block
+                    ),
+                }
+
+                return Err(EoServerError::ProviderLimit {
+                    from_block,
+                    to_block,
+                    source: std::sync::Arc::new(err),
+                });
+            }
+            Err(err) => {
+                return Err(EoServerError::rpc(
+                    format!(
+                        "failed to fetch logs for blocks {} to {}",
+                        from_block, to_block
+                    ),
+                    err,
+                ));
+            }
+        };
+
+        let mut logs_by_event = vec![Vec::new(); self.registry.len()];
+        for log in logs {
+            match self.registry.position(&log) {
+                Some(index) => logs_by_event[index].push(log),
+                None => log::warn!("ignoring log with unknown topic: {:?}", log.topics.first()),
+            }
         }
 
-        None
-    }
+        let mut events = Vec::new();
+        let mut errors = Vec::new();
+        for (index, (portion, logs)) in portions.into_iter().zip(logs_by_event).enumerate() {
+            let Some((start, end)) = portion else {
+                continue;
+            };
+
+            // Logs outside the portion were either scanned before or are not
+            // confirmed yet for this event
+            let logs = logs
+                .into_iter()
+                .filter(|log| {
+                    log.block_number
+                        .is_some_and(|block| start <= block && block <= end)
+                })
+                .collect();
+            let (decoded, decode_errors) = self.handle_logs(index, logs);
+            if !decode_errors.is_empty() {
+                errors.extend(decode_errors);
+                continue;
+            }
+            events.extend(decoded);
 
-    fn inner_lowest_bridge_block_processed(&self) -> Option<&U64> {
-        self.bridge_processed_blocks.first()
+            self.registry
+                .at_mut(index)
+                .scheduler_mut()
+                .complete(start, end);
+        }
+        if !events.is_empty() {
+            log::info!("discovered logs: logs.len() = {}", events.len());
+        }
+
+        Ok((events, errors))
     }
 
-    fn inner_lowest_bridge_block_processed_owned(&self) -> Option<U64> {
-        if let Some(b) = self.bridge_processed_blocks.first() {
-            return Some(b.clone());
+    /// The highest block whose events may be released for every registered
+    /// event, in registration order, given the latest block
+    pub(crate) async fn confirmed_blocks(&self, head: U64) -> Result<Vec<U64>, EoServerError> {
+        let eth = self.web3.eth();
+        let mut resolved: Vec<(Confirmation, U64)> = Vec::new();
+        let mut confirmed = Vec::with_capacity(self.registry.len());
+        for event in self.registry.iter() {
+            let confirmation = event.confirmation();
+            let block = match resolved.iter().find(|(c, _)| *c == confirmation) {
+                Some((_, block)) => *block,
+                None => {
+                    let block = self
+                        .rpc("confirmed block", || {
+                            confirmation.confirmed_block_at(&eth, head)
+                        })
+                        .await?;
+                    resolved.push((confirmation, block));
+                    block
+                }
+            };
+            confirmed.push(block);
         }
 
-        None
+        Ok(confirmed)
     }
 
-    fn bridge_block_processed(&self, block_number: &U64) -> bool {
-        self.bridge_processed_blocks.contains(block_number)
+    /// Decode logs of the event at `index` in the registry, recording them
+    /// for reorg detection or retracting them if they were removed.
+    ///
+    /// If any log fails to decode, nothing is recorded and only the errors
+    /// are returned, so the logs can be handled again once they decode.
+    pub(crate) fn handle_logs(&mut self, index: usize, logs: Vec<Log>) -> DecodedLogs {
+        let event = self.registry.at(index);
+        let name = event.name().to_string();
+        let decoded: Vec<(bool, Result<EoEvent, EoServerError>)> = logs
+            .iter()
+            .map(|log| (log.removed == Some(true), event.decode(log)))
+            .collect();
+
+        let errors: Vec<EoServerError> = decoded
+            .iter()
+            .filter_map(|(_, decoded)| decoded.as_ref().err().cloned())
+            .collect();
+        if !errors.is_empty() {
+            for err in &errors {
+                log::error!("failed to decode {} log: {}", name, err);
+            }
+            return (Vec::new(), errors);
+        }
+
+        let mut parsed_events = Vec::new();
+        for (removed, decoded) in decoded {
+            let Ok(event) = decoded else {
+                continue;
+            };
+            if removed {
+                parsed_events.push(self.retract_event(event));
+            } else if self.record_event(&event) {
+                parsed_events.push(event);
+            } else {
+                log::debug!("skipping already delivered {} event {}", name, event.id());
+            }
+        }
+        (parsed_events, errors)
     }
 
     async fn get_account_balance_eth(
@@ -468,7 +644,8 @@ impl EoServer {
         address: H160,
         block: Option<BlockNumber>,
     ) -> Result<web3::types::U256, web3::Error> {
-        self.web3.eth().balance(address, block).await
+        self.rpc("eth_getBalance", || self.web3.eth().balance(address, block))
+            .await
     }
 
     async fn get_batch_account_balance_eth(
@@ -485,7 +662,7 @@ impl EoServer {
         Ok(balances)
     }
 
-    async fn get_account_contract_data<T, R, A, B, P>(
+    async fn get_account_contract_data<R, A, B, P>(
         &mut self,
         address: A,
         contract: Contract<T>,
@@ -495,28 +672,33 @@ impl EoServer {
         options: Options,
     ) -> Result<R, web3::contract::Error>
     where
-        T: Transport,
         R: Detokenize,
-        A: Into<Option<Address>>,
-        B: Into<Option<BlockId>>,
-        P: Tokenize,
+        A: Into<Option<Address>> + Clone,
+        B: Into<Option<BlockId>> + Clone,
+        P: Tokenize + Clone,
     {
-        contract
-            .query::<R, A, B, P>(function, params, address, options, block)
-            .await
+        self.rpc("eth_call", || {
+            contract.query::<R, A, B, P>(
+                function,
+                params.clone(),
+                address.clone(),
+                options.clone(),
+                block.clone(),
+            )
+        })
+        .await
     }
 
-    async fn get_batch_account_contract_data<T, R, A, B, P>(
+    async fn get_batch_account_contract_data<R, A, B, P>(
         &mut self,
         account_contract_data: impl IntoIterator<Item = (A, Contract<T>, &str, P, Options)>,
         block: B,
     ) -> Result<Vec<(A, R)>, web3::contract::Error>
     where
-        T: Transport,
         R: Detokenize,
         A: Into<Option<Address>> + Clone,
         B: Into<Option<BlockId>> + Clone,
-        P: Tokenize,
+        P: Tokenize + Clone,
     {
         let mut results = Vec::new();
         for p in account_contract_data {
@@ -529,28 +711,86 @@ impl EoServer {
         Ok(results)
     }
 
-    pub fn contract(&self) -> &Contract<Http> {
+    pub fn contract(&self) -> &Contract<T> {
         &self.contract
     }
 
-    pub fn save_blocks_processed(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let blocks_processed = BlocksProcessed {
-            bridge: Some(self.current_bridge_filter_block.clone()),
-            settle: Some(self.current_blob_settlement_filter_block.clone()),
-            bridge_processed: self.bridge_processed_blocks.clone(),
-            settled_processed: self.settled_processed_blocks.clone(),
-        };
+    pub fn registry(&self) -> &EventRegistry {
+        &self.registry
+    }
+
+    /// The block ranges scanned so far for each registered event, and the
+    /// recent blocks and events a reorg would roll back
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            events: self
+                .registry
+                .iter()
+                .map(|event| {
+                    let scheduler = event.scheduler();
+                    let saved = EventCheckpoint::new(scheduler.completed().clone())
+                        .with_start_block(scheduler.start_block());
+                    (event.name().to_string(), saved)
+                })
+                .collect(),
+            delivered: self.delivered.clone(),
+            block_hashes: self.block_hashes.clone(),
+            recent_events: self.recent_events.clone(),
+        }
+    }
 
-        let bytes = bincode::serialize(&blocks_processed)?;
+    pub fn save_blocks_processed(&self) -> Result<(), EoServerError> {
+        self.checkpoint_store.save(&self.checkpoint())
+    }
 
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&self.path)?;
+    pub fn checkpoint_store(&self) -> &dyn CheckpointStore {
+        self.checkpoint_store.as_ref()
+    }
+}
 
-        file.write(&bytes)?;
+impl<T> EoServer<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    /// Run the polling listener on its own task, see `run`. Events are
+    /// buffered up to `DEFAULT_EVENT_BUFFER`, after that polling pauses until
+    /// the consumer receives more.
+    pub fn spawn(self) -> (EoServerHandle, mpsc::Receiver<EoEvent>) {
+        self.spawn_with_buffer(DEFAULT_EVENT_BUFFER)
+    }
 
-        Ok(())
+    pub fn spawn_with_buffer(self, buffer: usize) -> (EoServerHandle, mpsc::Receiver<EoEvent>) {
+        let (events, receiver) = mpsc::channel(buffer);
+        let (stop, stop_receiver) = oneshot::channel();
+        let acks = self.acknowledger();
+        let task = tokio::spawn(self.run(events, stop_receiver));
+        (EoServerHandle::new(task, stop, acks), receiver)
+    }
+}
+
+/// Send `batch` to the consumer in order, waiting for room in the channel so
+/// that a slow consumer pauses the listener instead of growing the buffer.
+/// Fails once the receiver has been dropped.
+pub(crate) async fn deliver(
+    events: &mpsc::Sender<EoEvent>,
+    batch: Vec<EoEvent>,
+) -> Result<(), mpsc::error::SendError<EoEvent>> {
+    for event in batch {
+        events.send(event).await?;
+    }
+    Ok(())
+}
+
+impl<T: Transport> EoServerBuilder<T> {
+    pub fn checkpoint_store<S: CheckpointStore + 'static>(&mut self, store: S) -> &mut Self {
+        self.checkpoint_store = Some(std::sync::Arc::new(store));
+        self
+    }
+
+    /// Checkpoint to a file at `path`
+    pub fn path<P: Into<std::path::PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.checkpoint_store(FileCheckpointStore::new(path))
     }
 }
 
@@ -562,8 +802,10 @@ impl EoAddress {
         EoAddress(address.to_string())
     }
 
-    pub fn parse(&self) -> Result<H160, rustc_hex::FromHexError> {
-        self.0.parse()
+    pub fn parse(&self) -> Result<H160, EoServerError> {
+        self.0
+            .parse()
+            .map_err(|e| EoServerError::invalid_address(&self.0, e))
     }
 }
 
@@ -1,7 +1,13 @@
-use eo_listener::{EoServer, EoServerError};
-use std::collections::BTreeSet;
-use std::str::FromStr;
-use web3::{transports::Http, types::BlockNumber, Web3};
+use eo_listener::{
+    BackfillConfig, BlobIndexSettledEvent, BridgeEvent, Confirmation, DeliveryGuarantee, EoEvent,
+    EoServer, EoServerError, EoServerHandle, EventRegistry, FailoverConfig, FailoverTransport,
+    RangeScheduler, Reconnect, RegisteredEvent, StartBlock,
+};
+use tokio::sync::mpsc::Receiver;
+use web3::{
+    transports::{Http, Ipc, WebSocket},
+    Transport, Web3,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), EoServerError> {
@@ -9,82 +15,408 @@ async fn main() -> Result<(), EoServerError> {
     simple_logger::init_with_level(log::Level::Info)
         .map_err(|e| EoServerError::Other(e.to_string()))?;
 
-    let eth_rpc_url = std::env::var("ETH_RPC_URL").expect("ETH_RPC_URL environment variable is not set. Please set the ETH_RPC_URL environment variable with the JSON/RPC HTTP endpoint.");
+    let path = "./blocks_processed.dat";
 
-    let http: Http =
-        Http::new(&eth_rpc_url).map_err(|err| EoServerError::Other(err.to_string()))?;
-    let web3: Web3<Http> = Web3::new(http);
+    // A comma separated list of HTTP endpoints to fail over between, with an
+    // optional quorum of them having to agree on every `eth_getLogs`
+    if let Ok(eth_rpc_urls) = std::env::var("ETH_RPC_URLS") {
+        let transport = failover_transport(&eth_rpc_urls)?;
+        return run(Web3::new(transport), path).await;
+    }
 
-    let path = "./blocks_processed.dat";
-    let eo_server = setup_eo_server(web3, path)?;
+    let eth_rpc_url = std::env::var("ETH_RPC_URL").expect("ETH_RPC_URL environment variable is not set. Please set the ETH_RPC_URL environment variable with the JSON/RPC HTTP, WebSocket or IPC endpoint.");
+
+    // The transport is picked from the URL scheme, anything without a
+    // scheme is treated as the path to an IPC socket. Duplex transports
+    // listen through a logs subscription, HTTP falls back to polling.
+    match eth_rpc_url.split_once("://") {
+        Some(("http", _)) | Some(("https", _)) => {
+            let http: Http =
+                Http::new(&eth_rpc_url).map_err(|err| connect_error(&eth_rpc_url, err))?;
+            run(Web3::new(http), path).await
+        }
+        Some(("ws", _)) | Some(("wss", _)) => {
+            let ws: WebSocket = WebSocket::new(&eth_rpc_url)
+                .await
+                .map_err(|err| connect_error(&eth_rpc_url, err))?;
+            run_subscribed(Web3::new(ws), path, &eth_rpc_url).await
+        }
+        Some(("ipc", socket_path)) => {
+            let ipc: Ipc = Ipc::new(socket_path)
+                .await
+                .map_err(|err| connect_error(socket_path, err))?;
+            run_subscribed(Web3::new(ipc), path, socket_path).await
+        }
+        Some((scheme, _)) => Err(EoServerError::Config(format!(
+            "Unsupported ETH_RPC_URL scheme: {}",
+            scheme
+        ))),
+        None => {
+            let ipc: Ipc = Ipc::new(&eth_rpc_url)
+                .await
+                .map_err(|err| connect_error(&eth_rpc_url, err))?;
+            run_subscribed(Web3::new(ipc), path, &eth_rpc_url).await
+        }
+    }
+}
+
+async fn run<T>(web3: Web3<T>, path: &str) -> Result<(), EoServerError>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    let mut eo_server = setup_eo_server(web3, path, None)?;
+    load_checkpoint(&mut eo_server).await?;
+
+    let (handle, events) = eo_server.spawn();
+    consume(handle, events).await
+}
+
+async fn run_subscribed<T>(web3: Web3<T>, path: &str, endpoint: &str) -> Result<(), EoServerError>
+where
+    T: Reconnect + Send + Sync + 'static,
+    T::Out: Send,
+    T::NotificationStream: Unpin + Send,
+{
+    let mut eo_server = setup_eo_server(web3, path, Some(endpoint))?;
+    load_checkpoint(&mut eo_server).await?;
+
+    let (handle, events) = eo_server.spawn_subscribed();
+    consume(handle, events).await
+}
+
+/// Log every event the listener delivers until it stops. SIGINT or SIGTERM
+/// stops the listener, which drains its last batch and saves its checkpoint.
+async fn consume(
+    mut handle: EoServerHandle,
+    mut events: Receiver<EoEvent>,
+) -> Result<(), EoServerError> {
+    let acks = handle.acknowledger();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut stopping = false;
 
-    let res = eo_server.run().await;
-    println!("{:?}", &res);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    log::info!("{} {:?}", event.id(), event);
+                    acks.ack();
+                }
+                None => break,
+            },
+            _ = &mut shutdown, if !stopping => {
+                log::info!("shutting down");
+                handle.stop();
+                stopping = true;
+            }
+        }
+    }
 
-    Ok(())
+    handle.join().await
 }
 
-fn setup_eo_server(
-    web3_instance: web3::Web3<web3::transports::Http>,
+fn failover_transport(eth_rpc_urls: &str) -> Result<FailoverTransport<Http>, EoServerError> {
+    let mut endpoints = Vec::new();
+    for url in eth_rpc_urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+    {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(EoServerError::Config(format!(
+                "ETH_RPC_URLS only supports HTTP endpoints: {}",
+                url
+            )));
+        }
+        let http = Http::new(url).map_err(|err| connect_error(url, err))?;
+        endpoints.push((url.to_string(), http));
+    }
+
+    let quorum = match std::env::var("EO_RPC_QUORUM") {
+        Ok(value) => Some(
+            value
+                .parse()
+                .map_err(|_| EoServerError::Config(format!("Invalid EO_RPC_QUORUM: {}", value)))?,
+        ),
+        Err(_) => None,
+    };
+    let config = FailoverConfig {
+        quorum,
+        ..Default::default()
+    };
+
+    FailoverTransport::new(endpoints, config).map_err(|e| EoServerError::Config(e.to_string()))
+}
+
+fn connect_error(endpoint: &str, err: web3::Error) -> EoServerError {
+    EoServerError::rpc(format!("failed to connect to {}", endpoint), err)
+}
+
+/// Resume from the saved checkpoint if there is one, legacy checkpoint files
+/// are migrated on load
+async fn load_checkpoint<T: Transport>(eo_server: &mut EoServer<T>) -> Result<(), EoServerError> {
+    eo_server.load_processed_blocks().await
+}
+
+fn setup_eo_server<T: Transport>(
+    web3_instance: web3::Web3<T>,
     path: &str,
-) -> Result<EoServer, EoServerError> {
+    endpoint: Option<&str>,
+) -> Result<EoServer<T>, EoServerError> {
     // Initialize the ExecutableOracle Address
     let eo_address_str = std::env::var("EO_CONTRACT_ADDRESS").expect("EO_CONTRACT_ADDRESS environment variable is not set. Please set the EO_CONTRACT_ADDRESS environment variable with the Executable Oracle contract address.");
     println!("{}", &eo_address_str);
     let eo_address = eo_listener::EoAddress::new(&eo_address_str);
-    let contract_address = eo_address
-        .parse()
-        .map_err(|err| EoServerError::Other(err.to_string()))?;
-    let contract_abi = eo_listener::get_abi()?;
+    let contract_address = eo_address.parse()?;
+    // A comma separated list of ABI files to look events up in, the ABI
+    // embedded at build time by default. Contract calls use the first one.
+    let abis = match std::env::var("EO_ABI_PATH") {
+        Ok(paths) => paths
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(eo_listener::load_abi)
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(_) => vec![eo_listener::get_abi()?],
+    };
+    let Some(contract_abi) = abis.first().cloned() else {
+        return Err(EoServerError::Config("EO_ABI_PATH is empty".to_string()));
+    };
     let address = web3::types::Address::from(contract_address);
     let contract = web3::contract::Contract::new(web3_instance.eth(), address, contract_abi);
 
-    let blob_settled_topic = eo_listener::get_blob_index_settled_topic();
-    let bridge_topic = eo_listener::get_bridge_event_topic();
-
-    let blob_settled_filter = web3::types::FilterBuilder::default()
-        .from_block(BlockNumber::Number(0.into()))
-        .to_block(BlockNumber::Latest)
-        .address(vec![contract_address])
-        .topics(blob_settled_topic.clone(), None, None, None)
-        .build();
-
-    let bridge_filter = web3::types::FilterBuilder::default()
-        .from_block(BlockNumber::Number(0.into()))
-        .to_block(BlockNumber::Latest)
-        .address(vec![contract_address])
-        .topics(bridge_topic.clone(), None, None, None)
-        .build();
-
-    let blob_settled_event = contract
-        .abi()
-        .event("BlobIndexSettled")
-        .map_err(|e| EoServerError::Other(e.to_string()))?
-        .clone();
-
-    let bridge_event = contract
-        .abi()
-        .event("Bridge")
-        .map_err(|e| EoServerError::Other(e.to_string()))?
-        .clone();
-
-    let eo_server = eo_listener::EoServerBuilder::default()
+    // The largest block range requested from `eth_getLogs` at once, ranges
+    // shrink below this whenever the provider rejects them
+    let max_block_range = match std::env::var("EO_MAX_BLOCK_RANGE") {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| EoServerError::Config(format!("Invalid EO_MAX_BLOCK_RANGE: {}", value)))?,
+        Err(_) => eo_listener::range::DEFAULT_MAX_CHUNK_SIZE,
+    };
+    let scheduler = RangeScheduler::default().with_chunk_sizes(
+        eo_listener::range::DEFAULT_CHUNK_SIZE,
+        eo_listener::range::DEFAULT_MIN_CHUNK_SIZE,
+        max_block_range,
+    );
+
+    // A comma separated list of the events to listen for, Bridge and
+    // BlobIndexSettled by default. Semicolons separate events given by their
+    // full signature, like "Transfer(address,address,uint256)", which the ABI
+    // is checked against.
+    let event_names = std::env::var("EO_EVENTS")
+        .unwrap_or_else(|_| format!("{},{}", BridgeEvent::NAME, BlobIndexSettledEvent::NAME));
+
+    // Filters on indexed parameters, applied by the node, as a semicolon
+    // separated list of `Event.param=value|value`, for example
+    // "Bridge.user=0xab..|0xcd..;Bridge.tokenAddress=0xef.."
+    let mut filters = match std::env::var("EO_INDEXED_FILTERS") {
+        Ok(value) => parse_indexed_filters(&value)?,
+        Err(_) => Vec::new(),
+    };
+
+    let mut registry = EventRegistry::new();
+    for spec in split_events(&event_names) {
+        let mut event = RegisteredEvent::from_abis(&abis, spec)?;
+        let name = event.name().to_string();
+        for (_, param, values) in filters.iter().filter(|(event, _, _)| *event == name) {
+            let values = values
+                .iter()
+                .map(|value| event.parse_indexed(param, value))
+                .collect::<Result<Vec<_>, _>>()?;
+            log::info!("filtering {} by {} ({} values)", name, param, values.len());
+            event = event.with_indexed_filter(param, values)?;
+        }
+        filters.retain(|(event, _, _)| *event != name);
+
+        let confirmation = confirmation_from_env(&confirmation_var(event.name()))?;
+        log::info!(
+            "listening for {} with topic {:?}",
+            eo_listener::event_signature(event.abi()),
+            event.topic()
+        );
+        registry.register(
+            event
+                .with_confirmation(confirmation)
+                .with_scheduler(scheduler.clone()),
+        )?;
+    }
+    if let Some((name, param, _)) = filters.first() {
+        return Err(EoServerError::Config(format!(
+            "EO_INDEXED_FILTERS filters {}.{}, but {} is not in EO_EVENTS",
+            name, param, name
+        )));
+    }
+
+    // Where to start without a checkpoint: a block number, "deployment" for
+    // the block the contract was deployed in, "latest" to only tail new
+    // blocks or "timestamp:<unix seconds>". Block 0 by default.
+    let start_block: Option<StartBlock> = match std::env::var("EO_START_BLOCK") {
+        Ok(value) => Some(value.parse()?),
+        Err(_) => None,
+    };
+
+    // Setting EO_BACKFILL_CONCURRENCY catches up on history with that many
+    // concurrent `eth_getLogs` calls before tailing the chain, at most
+    // EO_BACKFILL_RPS of them started per second if that is set too
+    let backfill = match std::env::var("EO_BACKFILL_CONCURRENCY") {
+        Ok(value) => Some(BackfillConfig {
+            concurrency: value.parse().map_err(|_| {
+                EoServerError::Config(format!("Invalid EO_BACKFILL_CONCURRENCY: {}", value))
+            })?,
+            requests_per_second: match std::env::var("EO_BACKFILL_RPS") {
+                Ok(value) => Some(value.parse().map_err(|_| {
+                    EoServerError::Config(format!("Invalid EO_BACKFILL_RPS: {}", value))
+                })?),
+                Err(_) => None,
+            },
+        }),
+        Err(_) => None,
+    };
+
+    // "at-least-once" (the default) only checkpoints acknowledged events,
+    // "at-most-once" checkpoints before delivering them
+    let delivery: DeliveryGuarantee = match std::env::var("EO_DELIVERY") {
+        Ok(value) => value.parse()?,
+        Err(_) => DeliveryGuarantee::default(),
+    };
+    let checkpoint_interval = match std::env::var("EO_CHECKPOINT_INTERVAL_SECS") {
+        Ok(value) => value
+            .parse()
+            .map(std::time::Duration::from_secs)
+            .map_err(|_| {
+                EoServerError::Config(format!("Invalid EO_CHECKPOINT_INTERVAL_SECS: {}", value))
+            })?,
+        Err(_) => eo_listener::DEFAULT_CHECKPOINT_INTERVAL,
+    };
+
+    let mut builder = eo_listener::EoServerBuilder::default();
+    // Checkpoints go to `path` unless EO_CHECKPOINT_DB names a SQLite
+    // database, which several listeners can share under their own
+    // EO_LISTENER_NAME
+    match std::env::var("EO_CHECKPOINT_DB") {
+        #[cfg(feature = "sqlite")]
+        Ok(db) => {
+            let listener = std::env::var("EO_LISTENER_NAME").unwrap_or(eo_address_str);
+            let store = eo_listener::SqliteCheckpointStore::open(&db, listener)
+                .map_err(|e| EoServerError::checkpoint(format!("failed to open {}", db), e))?;
+            builder.checkpoint_store(store);
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Ok(_) => {
+            return Err(EoServerError::Config(
+                "EO_CHECKPOINT_DB is set but the sqlite feature is disabled".to_string(),
+            ));
+        }
+        Err(_) => {
+            builder.path(path);
+        }
+    }
+
+    let eo_server = builder
         .web3(web3_instance)
         .eo_address(eo_address)
+        // Polls use this until the block time has been measured from the
+        // timestamps of head blocks
         .block_time(std::time::Duration::from_millis(2500))
-        .bridge_processed_blocks(BTreeSet::new())
-        .settled_processed_blocks(BTreeSet::new())
         .contract(contract)
-        .bridge_topic(bridge_topic)
-        .blob_settled_topic(blob_settled_topic)
-        .bridge_filter(bridge_filter)
-        .current_bridge_filter_block(0.into())
-        .current_blob_settlement_filter_block(0.into())
-        .blob_settled_filter(blob_settled_filter)
-        .blob_settled_event(blob_settled_event)
-        .bridge_event(bridge_event)
-        .path(std::path::PathBuf::from_str(path).map_err(|e| EoServerError::Other(e.to_string()))?)
+        .registry(registry)
+        .endpoint(endpoint.map(str::to_string))
+        .start_block(start_block)
+        .backfill(backfill)
+        .delivery(delivery)
+        .checkpoint_interval(checkpoint_interval)
         .build()?;
 
     Ok(eo_server)
 }
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => log::warn!("failed to listen for SIGTERM: {}", err),
+        }
+    }
+
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        log::warn!("failed to listen for SIGINT: {}", err);
+        futures::future::pending::<()>().await;
+    }
+}
+
+fn confirmation_from_env(var: &str) -> Result<Confirmation, EoServerError> {
+    match std::env::var(var) {
+        Ok(value) => value.parse(),
+        Err(_) => Ok(Confirmation::default()),
+    }
+}
+
+/// The variable holding how many confirmations the event called `name` waits
+/// for: a block count, "latest", "safe" or "finalized". BlobIndexSettled
+/// keeps its original EO_SETTLEMENT_CONFIRMATION, other events use their name
+/// in upper snake case, like EO_BRIDGE_CONFIRMATION.
+fn confirmation_var(name: &str) -> String {
+    if name == BlobIndexSettledEvent::NAME {
+        return "EO_SETTLEMENT_CONFIRMATION".to_string();
+    }
+
+    let mut var = String::from("EO_");
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            var.push('_');
+        }
+        var.push(c.to_ascii_uppercase());
+    }
+    var.push_str("_CONFIRMATION");
+    var
+}
+
+/// Split EO_EVENTS into event names and signatures. Signatures contain
+/// commas themselves, so a list with any parenthesis is split on semicolons.
+fn split_events(events: &str) -> Vec<&str> {
+    let separator = if events.contains('(') { ';' } else { ',' };
+    events
+        .split(separator)
+        .map(str::trim)
+        .filter(|event| !event.is_empty())
+        .collect()
+}
+
+/// Parse EO_INDEXED_FILTERS into (event, parameter, values)
+fn parse_indexed_filters(value: &str) -> Result<Vec<(String, String, Vec<String>)>, EoServerError> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|filter| !filter.is_empty())
+        .map(|filter| {
+            let invalid = || {
+                EoServerError::Config(format!(
+                    "Invalid EO_INDEXED_FILTERS entry {:?}, expected Event.param=value|value",
+                    filter
+                ))
+            };
+            let (target, values) = filter.split_once('=').ok_or_else(invalid)?;
+            let (event, param) = target.trim().split_once('.').ok_or_else(invalid)?;
+            let values: Vec<String> = values
+                .split('|')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect();
+            if values.is_empty() {
+                return Err(invalid());
+            }
+
+            Ok((event.trim().to_string(), param.trim().to_string(), values))
+        })
+        .collect()
+}
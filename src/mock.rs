@@ -0,0 +1,159 @@
+//! A `Transport` that answers from a closure, for testing code that makes
+//! RPC calls without a node
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use jsonrpc_core::types::error::{Error as RpcError, ErrorCode};
+use jsonrpc_core::{Call, Params, Value};
+use web3::{api::SubscriptionId, helpers::build_request, DuplexTransport, RequestId, Transport};
+
+use crate::{
+    get_abi, BlobIndexSettledEvent, BridgeEvent, EoAddress, EoServerBuilder, EventRegistry,
+    MemoryCheckpointStore, Reconnect,
+};
+
+type Handler = dyn Fn(&str, &[Value]) -> web3::Result<Value> + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct MockTransport {
+    handler: Arc<Handler>,
+    delay: Duration,
+    ids: Arc<AtomicUsize>,
+    calls: Arc<Mutex<Vec<String>>>,
+    /// Where notifications of the open subscriptions are sent
+    subscriptions: Arc<Mutex<Vec<mpsc::UnboundedSender<Value>>>>,
+}
+
+impl std::fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockTransport")
+            .field("delay", &self.delay)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockTransport {
+    /// Answer every call with `handler(method, params)`
+    pub(crate) fn new(
+        handler: impl Fn(&str, &[Value]) -> web3::Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        MockTransport {
+            handler: Arc::new(handler),
+            delay: Duration::ZERO,
+            ids: Arc::default(),
+            calls: Arc::default(),
+            subscriptions: Arc::default(),
+        }
+    }
+
+    /// Wait `delay` before answering every call
+    pub(crate) fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// How many calls of `method` were sent so far
+    pub(crate) fn calls(&self, method: &str) -> usize {
+        let calls = self.calls.lock().unwrap();
+        calls.iter().filter(|called| *called == method).count()
+    }
+
+    /// Send `notification` to the newest subscription
+    pub(crate) fn notify(&self, notification: Value) {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let subscription = subscriptions.last().expect("nothing subscribed");
+        subscription.unbounded_send(notification).unwrap();
+    }
+}
+
+impl Transport for MockTransport {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        let id = self.ids.fetch_add(1, Ordering::AcqRel);
+        (id, build_request(id, method, params))
+    }
+
+    fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+        let (method, params) = match request {
+            Call::MethodCall(call) => match call.params {
+                Params::Array(params) => (call.method, params),
+                _ => (call.method, Vec::new()),
+            },
+            _ => (String::new(), Vec::new()),
+        };
+        self.calls.lock().unwrap().push(method.clone());
+
+        // Subscriptions always succeed, their notifications come from `notify`
+        let subscribed = match method.as_str() {
+            "eth_subscribe" => Some(Value::from(format!("{:#x}", self.calls("eth_subscribe")))),
+            "eth_unsubscribe" => Some(Value::Bool(true)),
+            _ => None,
+        };
+        if let Some(answer) = subscribed {
+            return Box::pin(async move { Ok(answer) });
+        }
+
+        let handler = self.handler.clone();
+        let delay = self.delay;
+        Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            handler(&method, &params)
+        })
+    }
+}
+
+impl DuplexTransport for MockTransport {
+    type NotificationStream = mpsc::UnboundedReceiver<Value>;
+
+    fn subscribe(&self, _id: SubscriptionId) -> web3::Result<Self::NotificationStream> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscriptions.lock().unwrap().push(sender);
+        Ok(receiver)
+    }
+
+    fn unsubscribe(&self, _id: SubscriptionId) -> web3::Result<()> {
+        Ok(())
+    }
+}
+
+impl Reconnect for MockTransport {
+    fn reconnect(_endpoint: &str) -> BoxFuture<'static, web3::Result<Self>> {
+        Box::pin(async { Err(web3::Error::Unreachable) })
+    }
+}
+
+/// A server over `transport` listening for Bridge and BlobIndexSettled
+/// events, checkpointing to memory
+pub(crate) fn server_builder(transport: MockTransport) -> EoServerBuilder<MockTransport> {
+    let web3 = web3::Web3::new(transport);
+    let abi = get_abi().unwrap();
+    let contract = web3::contract::Contract::new(web3.eth(), Default::default(), abi.clone());
+    let registry =
+        EventRegistry::from_abis(&[abi], [BridgeEvent::NAME, BlobIndexSettledEvent::NAME]).unwrap();
+
+    let mut builder = EoServerBuilder::default();
+    builder
+        .web3(web3)
+        .eo_address(EoAddress::new("0x0000000000000000000000000000000000000000"))
+        .block_time(Duration::from_secs(12))
+        .contract(contract)
+        .registry(registry)
+        .checkpoint_store(MemoryCheckpointStore::new());
+    builder
+}
+
+/// An error response from the node
+pub(crate) fn rpc_error(code: i64, message: &str) -> web3::Error {
+    web3::Error::Rpc(RpcError {
+        code: ErrorCode::from(code),
+        message: message.to_string(),
+        data: None,
+    })
+}
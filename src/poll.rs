@@ -0,0 +1,219 @@
+use std::time::{Duration, Instant};
+
+use web3::{types::U64, Transport};
+
+use crate::{EoServer, EoServerError};
+
+/// The longest the listener waits between polls while the head stands still
+pub const DEFAULT_MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Block times measured below this are rounded up to it
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How many times in a row the poll interval doubles while the head does not
+/// move, before `max_poll_interval` caps it anyway
+const MAX_IDLE_DOUBLINGS: u32 = 6;
+
+/// What the poll loop has observed of the chain: the cached head, the block
+/// time measured from head timestamps and whether the last scan left blocks
+/// behind
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PollState {
+    /// The latest head and when it was fetched
+    head: Option<(U64, Instant)>,
+    /// The number and timestamp of the latest head block seen
+    head_block: Option<(U64, u64)>,
+    /// Moving average of the time between blocks
+    block_time: Option<Duration>,
+    /// The head at the previous poll
+    polled_head: Option<U64>,
+    /// Polls in a row that found the head where it was
+    idle_polls: u32,
+    /// Whether the last scan stopped short of the confirmed blocks
+    pub(crate) behind: bool,
+}
+
+impl PollState {
+    /// The head, if it was fetched less than `ttl` ago
+    fn cached_head(&self, ttl: Duration) -> Option<U64> {
+        self.head
+            .filter(|(_, fetched)| fetched.elapsed() < ttl)
+            .map(|(head, _)| head)
+    }
+
+    fn record_head(&mut self, head: U64) {
+        self.head = Some((head, Instant::now()));
+    }
+
+    /// Fold the time between the previous head block and this one into the
+    /// block time estimate
+    fn record_head_block(&mut self, number: U64, timestamp: u64) {
+        if let Some((previous, previous_timestamp)) = self.head_block {
+            if number > previous && timestamp >= previous_timestamp {
+                let blocks = (number - previous).as_u64() as f64;
+                let sample =
+                    Duration::from_secs_f64((timestamp - previous_timestamp) as f64 / blocks);
+                self.block_time = Some(match self.block_time {
+                    Some(average) => (average * 3 + sample) / 4,
+                    None => sample,
+                });
+            }
+        }
+
+        if self
+            .head_block
+            .is_none_or(|(previous, _)| number >= previous)
+        {
+            self.head_block = Some((number, timestamp));
+        }
+    }
+}
+
+impl<T: Transport> EoServer<T> {
+    /// The time between blocks as measured from head timestamps, or
+    /// `block_time` until it has been
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll.block_time.unwrap_or(self.block_time).clamp(
+            MIN_POLL_INTERVAL,
+            self.max_poll_interval.max(MIN_POLL_INTERVAL),
+        )
+    }
+
+    /// How long to wait before the next poll: not at all while the last scan
+    /// left blocks behind, one block time once caught up, and twice as long
+    /// for every poll in a row that found the head where it was
+    pub(crate) fn poll_delay(&mut self) -> Duration {
+        if self.poll.behind {
+            self.poll.idle_polls = 0;
+            return Duration::ZERO;
+        }
+
+        let head = self.poll.head.map(|(head, _)| head);
+        if head.is_some() && head == self.poll.polled_head {
+            self.poll.idle_polls = self.poll.idle_polls.saturating_add(1);
+        } else {
+            self.poll.idle_polls = 0;
+        }
+        self.poll.polled_head = head;
+
+        let backoff = 1 << self.poll.idle_polls.min(MAX_IDLE_DOUBLINGS);
+        std::cmp::min(self.poll_interval() * backoff, self.max_poll_interval)
+    }
+
+    /// The number of the latest block. It is cached for half a block time,
+    /// so the calls made for a single poll share one `eth_blockNumber`.
+    pub(crate) async fn head(&mut self) -> Result<U64, EoServerError> {
+        if let Some(head) = self.poll.cached_head(self.poll_interval() / 2) {
+            return Ok(head);
+        }
+
+        let head = self
+            .rpc("eth_blockNumber", || self.web3.eth().block_number())
+            .await
+            .map_err(|e| EoServerError::rpc("failed to get the latest block number", e))?;
+        self.poll.record_head(head);
+        Ok(head)
+    }
+
+    /// Record a freshly fetched head block, refreshing the cached head and
+    /// the block time estimate
+    pub(crate) fn record_head_block(&mut self, number: U64, timestamp: u64) {
+        self.poll.record_head(number);
+        self.poll.record_head_block(number, timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::{rpc_error, server_builder, MockTransport};
+    use crate::{get_abi, BridgeEvent, EventRegistry, RangeScheduler, RegisteredEvent};
+
+    #[test]
+    fn averages_the_time_between_head_blocks() {
+        let mut poll = PollState::default();
+        poll.record_head_block(U64::from(100), 1_000);
+        assert_eq!(poll.block_time, None);
+
+        poll.record_head_block(U64::from(101), 1_012);
+        assert_eq!(poll.block_time, Some(Duration::from_secs(12)));
+
+        // Spread over the blocks in between, then folded into the average
+        poll.record_head_block(U64::from(103), 1_018);
+        assert_eq!(poll.block_time, Some(Duration::from_millis(9_750)));
+
+        // An older head says nothing about the block time
+        poll.record_head_block(U64::from(102), 1_015);
+        assert_eq!(poll.block_time, Some(Duration::from_millis(9_750)));
+        assert_eq!(poll.head_block, Some((U64::from(103), 1_018)));
+    }
+
+    #[test]
+    fn backs_off_while_the_head_stands_still() {
+        let mut server = server_builder(MockTransport::new(|_, _| Err(web3::Error::Unreachable)))
+            .block_time(Duration::from_secs(2))
+            .max_poll_interval(Duration::from_secs(10))
+            .build()
+            .unwrap();
+
+        server.poll.record_head(U64::from(5));
+        let delays: Vec<Duration> = (0..5).map(|_| server.poll_delay()).collect();
+        assert_eq!(delays, [2, 4, 8, 10, 10].map(Duration::from_secs).to_vec());
+
+        // A new head resets the backoff, so does falling behind
+        server.poll.record_head(U64::from(6));
+        assert_eq!(server.poll_delay(), Duration::from_secs(2));
+        server.poll_delay();
+        server.poll.behind = true;
+        assert_eq!(server.poll_delay(), Duration::ZERO);
+        server.poll.behind = false;
+        assert_eq!(server.poll_delay(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn clamps_the_measured_block_time() {
+        let mut server = server_builder(MockTransport::new(|_, _| Err(web3::Error::Unreachable)))
+            .max_poll_interval(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        // Until a block time is measured, the configured one is capped
+        assert_eq!(server.poll_interval(), Duration::from_secs(10));
+
+        server.record_head_block(U64::from(1), 100);
+        server.record_head_block(U64::from(2), 100);
+        assert_eq!(server.poll_interval(), MIN_POLL_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_right_away_at_the_smallest_chunk_size() {
+        let node = MockTransport::new(|method, _| match method {
+            "eth_blockNumber" => Ok(json!("0x3e8")),
+            "eth_getLogs" => Err(rpc_error(-32005, "query returned more than 10000 results")),
+            _ => Err(web3::Error::Unreachable),
+        });
+        let event = RegisteredEvent::from_abi(&get_abi().unwrap(), BridgeEvent::NAME)
+            .unwrap()
+            .with_scheduler(RangeScheduler::default().with_chunk_sizes(100, 25, 100));
+        let mut registry = EventRegistry::new();
+        registry.register(event).unwrap();
+        let mut server = server_builder(node).registry(registry).build().unwrap();
+
+        // Each rejected range is retried right away in smaller chunks...
+        for chunk_size in [50, 25] {
+            assert!(matches!(
+                server.scan().await,
+                Err(EoServerError::ProviderLimit { .. })
+            ));
+            assert!(server.poll.behind);
+            assert_eq!(server.chunk_sizes(), vec![chunk_size]);
+        }
+
+        // ...until they cannot shrink any further
+        assert!(matches!(
+            server.scan().await,
+            Err(EoServerError::ProviderLimit { .. })
+        ));
+        assert!(!server.poll.behind);
+        assert_eq!(server.chunk_sizes(), vec![25]);
+    }
+}
@@ -0,0 +1,592 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use web3::types::U64;
+
+use crate::rpc::is_rate_limit_error;
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 1_000;
+pub const DEFAULT_MIN_CHUNK_SIZE: u64 = 1;
+pub const DEFAULT_MAX_CHUNK_SIZE: u64 = 10_000;
+
+/// A set of inclusive block ranges. Overlapping and adjacent ranges are
+/// merged on insert, so the set stays as small as the gaps between them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockRanges(BTreeMap<U64, U64>);
+
+impl BlockRanges {
+    pub fn insert(&mut self, start: U64, end: U64) {
+        if start > end {
+            return;
+        }
+
+        let (mut start, mut end) = (start, end);
+        let merged: Vec<(U64, U64)> = self
+            .0
+            .range(..=end.saturating_add(U64::one()))
+            .rev()
+            .take_while(|(_, e)| e.saturating_add(U64::one()) >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+
+        for (s, e) in merged {
+            self.0.remove(&s);
+            start = std::cmp::min(start, s);
+            end = std::cmp::max(end, e);
+        }
+
+        self.0.insert(start, end);
+    }
+
+    pub fn contains(&self, block: U64) -> bool {
+        self.covered_until(block).is_some()
+    }
+
+    /// Forget every block from `block` onwards
+    pub fn remove_from(&mut self, block: U64) {
+        self.0.split_off(&block);
+        if let Some((_, end)) = self.0.iter_mut().next_back() {
+            if *end >= block {
+                *end = block - U64::one();
+            }
+        }
+    }
+
+    /// The ranges between `from` and `to` (inclusive) that are not covered
+    pub fn gaps(&self, from: U64, to: U64) -> Vec<(U64, U64)> {
+        let mut gaps = Vec::new();
+        let mut next = from;
+        for (start, end) in self.iter() {
+            if next > to {
+                break;
+            }
+            if end < next {
+                continue;
+            }
+            if start > next {
+                gaps.push((next, std::cmp::min(start - U64::one(), to)));
+            }
+            next = end.saturating_add(U64::one());
+            if end == U64::MAX {
+                return gaps;
+            }
+        }
+
+        if next <= to {
+            gaps.push((next, to));
+        }
+        gaps
+    }
+
+    /// The end of the range covering `block`, if any
+    pub fn covered_until(&self, block: U64) -> Option<U64> {
+        self.0
+            .range(..=block)
+            .next_back()
+            .map(|(_, end)| *end)
+            .filter(|end| *end >= block)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (U64, U64)> + '_ {
+        self.0.iter().map(|(s, e)| (*s, *e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn first_block(&self) -> Option<U64> {
+        self.0.keys().next().copied()
+    }
+
+    pub fn last_block(&self) -> Option<U64> {
+        self.0.values().next_back().copied()
+    }
+}
+
+/// Splits a scan of `eth_getLogs` into chunks that providers will accept.
+///
+/// The chunk is halved whenever the provider rejects a range for being too
+/// large or returning too many results, and doubled again after each range
+/// that succeeds. Only ranges passed to `complete` are recorded as scanned.
+///
+/// Whatever heads it is given and whichever scans fail, the scheduler keeps
+/// these invariants:
+///
+/// - No gaps: every block from the start block up to `next_block - 1` has
+///   been scanned, and `next_block` only moves past blocks that have.
+/// - Monotonic progress: `next_block` never moves backwards except through
+///   `rewind`, never below the start block, and each completed scan moves
+///   it forward.
+/// - Bounded overlap: a range returned by `next_range` or `portion` starts
+///   at `next_block`, so no block is scanned twice unless it was rewound.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeScheduler {
+    /// Nothing before this block is ever scanned, not even after a rewind
+    start_block: U64,
+    next_block: U64,
+    chunk_size: u64,
+    min_chunk_size: u64,
+    max_chunk_size: u64,
+    completed: BlockRanges,
+}
+
+impl Default for RangeScheduler {
+    fn default() -> Self {
+        RangeScheduler::new(U64::zero())
+    }
+}
+
+impl RangeScheduler {
+    pub fn new(start_block: U64) -> Self {
+        RangeScheduler {
+            start_block,
+            next_block: start_block,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            completed: BlockRanges::default(),
+        }
+    }
+
+    pub fn with_chunk_sizes(mut self, initial: u64, min: u64, max: u64) -> Self {
+        self.min_chunk_size = min.max(1);
+        self.max_chunk_size = max.max(self.min_chunk_size);
+        self.chunk_size = initial.clamp(self.min_chunk_size, self.max_chunk_size);
+        self
+    }
+
+    /// The next range to scan, or `None` once `head` has been reached
+    pub fn next_range(&self, head: U64) -> Option<(U64, U64)> {
+        if self.next_block > head {
+            return None;
+        }
+
+        let end = self
+            .next_block
+            .saturating_add(U64::from(self.chunk_size - 1));
+        Some((self.next_block, std::cmp::min(end, head)))
+    }
+
+    /// The part of `from..=to` this scheduler still has to scan, given that
+    /// nothing after `confirmed` may be scanned yet
+    pub fn portion(&self, from: U64, to: U64, confirmed: U64) -> Option<(U64, U64)> {
+        let start = std::cmp::max(from, self.next_block);
+        let end = std::cmp::min(to, confirmed);
+        (start <= end).then_some((start, end))
+    }
+
+    /// Start scanning at `block` instead, forgetting everything scanned so far
+    pub fn start_at(&mut self, block: U64) {
+        self.start_block = block;
+        self.next_block = block;
+        self.completed = BlockRanges::default();
+    }
+
+    /// Record `from..=to` as scanned and grow the chunk size
+    pub fn complete(&mut self, from: U64, to: U64) {
+        self.completed.insert(from, to);
+        self.skip_completed();
+        self.chunk_size = self.chunk_size.saturating_mul(2).min(self.max_chunk_size);
+    }
+
+    /// Record everything from the next block up to `block` as scanned without
+    /// touching the chunk size, used when the range was covered some other
+    /// way (a checkpoint or a subscription)
+    pub fn scanned_through(&mut self, block: U64) {
+        if block >= self.next_block {
+            self.completed.insert(self.next_block, block);
+            self.skip_completed();
+        }
+    }
+
+    /// Merge in ranges restored from a checkpoint
+    pub fn restore(&mut self, scanned: &BlockRanges) {
+        for (start, end) in scanned.iter() {
+            self.completed.insert(start, end);
+        }
+        self.skip_completed();
+    }
+
+    fn skip_completed(&mut self) {
+        if let Some(end) = self.completed.covered_until(self.next_block) {
+            self.next_block = end.saturating_add(U64::one());
+        }
+    }
+
+    /// The provider rejected the last range as too large
+    pub fn shrink(&mut self) -> u64 {
+        self.chunk_size = (self.chunk_size / 2).max(self.min_chunk_size);
+        self.chunk_size
+    }
+
+    /// Scan again from `block`, forgetting that anything after it was done
+    pub fn rewind(&mut self, block: U64) {
+        let block = std::cmp::max(block, self.start_block);
+        self.next_block = std::cmp::min(self.next_block, block);
+        self.completed.remove_from(block);
+    }
+
+    /// The block scanning started at, see `start_at`
+    pub fn start_block(&self) -> U64 {
+        self.start_block
+    }
+
+    pub fn next_block(&self) -> U64 {
+        self.next_block
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn completed(&self) -> &BlockRanges {
+        &self.completed
+    }
+}
+
+/// The range of a single `eth_getLogs` call covering several schedulers,
+/// each paired with the highest block it may scan up to.
+///
+/// The range starts at the earliest block any of them still has to scan, is
+/// no longer than the smallest of their chunk sizes and ends no later than
+/// the highest of their confirmed blocks. `None` once all of them have
+/// caught up.
+pub fn merged_range<'a>(
+    schedulers: impl IntoIterator<Item = (&'a RangeScheduler, U64)>,
+) -> Option<(U64, U64)> {
+    let mut merged: Option<(U64, u64, U64)> = None;
+    for (scheduler, confirmed) in schedulers {
+        let Some((from, _)) = scheduler.next_range(confirmed) else {
+            continue;
+        };
+
+        merged = Some(match merged {
+            None => (from, scheduler.chunk_size, confirmed),
+            Some((start, chunk_size, end)) => (
+                std::cmp::min(start, from),
+                std::cmp::min(chunk_size, scheduler.chunk_size),
+                std::cmp::max(end, confirmed),
+            ),
+        });
+    }
+
+    let (from, chunk_size, confirmed) = merged?;
+    let to = from.saturating_add(U64::from(chunk_size - 1));
+    Some((from, std::cmp::min(to, confirmed)))
+}
+
+/// The next `eth_getLogs` call of a scan over several schedulers: the
+/// merged range to fetch and, per scheduler, the part of it that scheduler
+/// still has to scan. Logs outside a scheduler's portion must be ignored for
+/// it, they were either scanned before or are not confirmed yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanPlan {
+    pub range: (U64, U64),
+    pub portions: Vec<Option<(U64, U64)>>,
+}
+
+/// Plan the next scan over `schedulers`, each paired with the highest block
+/// it may scan up to, see `merged_range`. `None` once all of them have caught
+/// up.
+///
+/// Every portion starts at its scheduler's next block and ends no later than
+/// its confirmed block, so completing the portions keeps the invariants of
+/// `RangeScheduler`.
+pub fn plan_scan<'a>(
+    schedulers: impl IntoIterator<Item = (&'a RangeScheduler, U64)>,
+) -> Option<ScanPlan> {
+    let schedulers: Vec<(&RangeScheduler, U64)> = schedulers.into_iter().collect();
+    let (from, to) = merged_range(schedulers.iter().copied())?;
+    let portions = schedulers
+        .iter()
+        .map(|(scheduler, confirmed)| scheduler.portion(from, to, *confirmed))
+        .collect();
+
+    Some(ScanPlan {
+        range: (from, to),
+        portions,
+    })
+}
+
+/// Whether the provider rejected an `eth_getLogs` call because the block
+/// range or the number of results was too large. Rate limits are not, even
+/// when they share the `-32005` code, see `is_rate_limit_error`.
+pub fn is_provider_limit_error(err: &web3::Error) -> bool {
+    const LIMIT_MESSAGES: [&str; 8] = [
+        "query returned more than",
+        "block range",
+        "range too large",
+        "range is too large",
+        "range limit",
+        "too many results",
+        "result size",
+        "response size",
+    ];
+
+    if is_rate_limit_error(err) {
+        return false;
+    }
+
+    let message = match err {
+        web3::Error::Rpc(rpc) => rpc.message.to_lowercase(),
+        other => other.to_string().to_lowercase(),
+    };
+
+    LIMIT_MESSAGES.iter().any(|m| message.contains(m))
+        // "eth_getLogs is limited to a 10000 range"
+        || (message.contains("is limited to") && message.contains("range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// What happens to the `eth_getLogs` call of one tick
+    #[derive(Clone, Copy, Debug)]
+    enum Outcome {
+        Ok,
+        ProviderLimit,
+        Failed,
+    }
+
+    #[derive(Clone, Debug)]
+    struct Tick {
+        head: u64,
+        outcome: Outcome,
+        /// Roll back this many blocks behind the head before scanning
+        reorg: Option<u64>,
+    }
+
+    fn outcome() -> impl Strategy<Value = Outcome> {
+        prop_oneof![
+            3 => Just(Outcome::Ok),
+            1 => Just(Outcome::ProviderLimit),
+            1 => Just(Outcome::Failed),
+        ]
+    }
+
+    fn tick() -> impl Strategy<Value = Tick> {
+        (0..600u64, outcome(), prop::option::weighted(0.1, 0..20u64)).prop_map(
+            |(head, outcome, reorg)| Tick {
+                head,
+                outcome,
+                reorg,
+            },
+        )
+    }
+
+    /// A scheduler and the number of blocks its event waits for
+    fn scheduler() -> impl Strategy<Value = (RangeScheduler, u64)> {
+        (0..100u64, 1..300u64, 1..50u64, 1..500u64, 0..10u64).prop_map(
+            |(start, initial, min, max, lag)| {
+                let scheduler =
+                    RangeScheduler::new(U64::from(start)).with_chunk_sizes(initial, min, max);
+                (scheduler, lag)
+            },
+        )
+    }
+
+    fn blocks(ranges: &BlockRanges) -> BTreeSet<u64> {
+        ranges
+            .iter()
+            .flat_map(|(start, end)| start.as_u64()..=end.as_u64())
+            .collect()
+    }
+
+    /// Check that the scheduler has scanned exactly the blocks from `start`
+    /// up to its next block
+    fn assert_no_gaps(scheduler: &RangeScheduler, start: u64) -> Result<(), TestCaseError> {
+        let next = scheduler.next_block().as_u64();
+        prop_assert!(next >= start);
+        let expected: BTreeSet<u64> = (start..next).collect();
+        prop_assert_eq!(blocks(scheduler.completed()), expected);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn scans_have_no_gaps_and_no_overlap(
+            mut schedulers in prop::collection::vec(scheduler(), 1..4),
+            ticks in prop::collection::vec(tick(), 0..80),
+        ) {
+            let starts: Vec<u64> = schedulers
+                .iter()
+                .map(|(scheduler, _)| scheduler.next_block().as_u64())
+                .collect();
+            // The blocks each scheduler scanned and has not rewound since
+            let mut scanned: Vec<BTreeSet<u64>> = vec![BTreeSet::new(); schedulers.len()];
+
+            for tick in ticks {
+                if let Some(depth) = tick.reorg {
+                    let block = U64::from(tick.head.saturating_sub(depth));
+                    for (((scheduler, _), scanned), start) in
+                        schedulers.iter_mut().zip(&mut scanned).zip(&starts)
+                    {
+                        let before = scheduler.next_block();
+                        scheduler.rewind(block);
+                        let block = std::cmp::max(block, U64::from(*start));
+                        prop_assert_eq!(scheduler.next_block(), std::cmp::min(before, block));
+                        scanned.retain(|b| *b < block.as_u64());
+                    }
+                }
+
+                let confirmed: Vec<U64> = schedulers
+                    .iter()
+                    .map(|(_, lag)| U64::from(tick.head.saturating_sub(*lag)))
+                    .collect();
+                let plan = plan_scan(
+                    schedulers
+                        .iter()
+                        .map(|(scheduler, _)| scheduler)
+                        .zip(confirmed.iter().copied()),
+                );
+
+                let behind: Vec<usize> = (0..schedulers.len())
+                    .filter(|i| schedulers[*i].0.next_block() <= confirmed[*i])
+                    .collect();
+                let Some(plan) = plan else {
+                    prop_assert!(behind.is_empty());
+                    continue;
+                };
+
+                let (from, to) = plan.range;
+                let min_chunk = behind
+                    .iter()
+                    .map(|i| schedulers[*i].0.chunk_size())
+                    .min()
+                    .unwrap();
+                prop_assert!(from <= to);
+                prop_assert!((to - from).as_u64() < min_chunk);
+                prop_assert_eq!(
+                    Some(from),
+                    behind.iter().map(|i| schedulers[*i].0.next_block()).min()
+                );
+                prop_assert!(to <= *confirmed.iter().max().unwrap());
+                // The scheduler furthest behind always gets work, so every
+                // successful tick makes progress
+                prop_assert!(plan.portions.iter().any(Option::is_some));
+
+                let before: Vec<U64> = schedulers
+                    .iter()
+                    .map(|(scheduler, _)| scheduler.next_block())
+                    .collect();
+                for (index, portion) in plan.portions.iter().enumerate() {
+                    let Some((start, end)) = *portion else {
+                        continue;
+                    };
+                    prop_assert_eq!(start, before[index]);
+                    prop_assert!(from <= start && start <= end && end <= to);
+                    prop_assert!(end <= confirmed[index]);
+                }
+
+                match tick.outcome {
+                    Outcome::Ok => {
+                        for (index, portion) in plan.portions.into_iter().enumerate() {
+                            let Some((start, end)) = portion else {
+                                continue;
+                            };
+                            for block in start.as_u64()..=end.as_u64() {
+                                prop_assert!(scanned[index].insert(block), "block {} scanned twice", block);
+                            }
+                            schedulers[index].0.complete(start, end);
+                            prop_assert_eq!(schedulers[index].0.next_block(), end + 1);
+                        }
+                    }
+                    Outcome::ProviderLimit => {
+                        for (scheduler, _) in schedulers.iter_mut() {
+                            let chunk_size = scheduler.chunk_size();
+                            prop_assert!(scheduler.shrink() <= chunk_size);
+                        }
+                    }
+                    Outcome::Failed => {}
+                }
+
+                for (index, (scheduler, _)) in schedulers.iter().enumerate() {
+                    if !matches!(tick.outcome, Outcome::Ok) {
+                        prop_assert_eq!(scheduler.next_block(), before[index]);
+                    }
+                    prop_assert!(scheduler.next_block() >= before[index]);
+                    assert_no_gaps(scheduler, starts[index])?;
+                }
+            }
+        }
+
+        #[test]
+        fn catches_up_to_a_fixed_head(
+            mut schedulers in prop::collection::vec(scheduler(), 1..4),
+            head in 0..600u64,
+        ) {
+            let starts: Vec<U64> = schedulers
+                .iter()
+                .map(|(scheduler, _)| scheduler.next_block())
+                .collect();
+            let confirmed: Vec<U64> = schedulers
+                .iter()
+                .map(|(_, lag)| U64::from(head.saturating_sub(*lag)))
+                .collect();
+
+            // Each scan covers at least one block of some scheduler
+            let mut remaining = schedulers.len() * (head as usize + 1);
+            while let Some(plan) = plan_scan(
+                schedulers
+                    .iter()
+                    .map(|(scheduler, _)| scheduler)
+                    .zip(confirmed.iter().copied()),
+            ) {
+                prop_assert!(remaining > 0, "no progress towards the head");
+                remaining -= 1;
+                for (index, portion) in plan.portions.into_iter().enumerate() {
+                    if let Some((start, end)) = portion {
+                        schedulers[index].0.complete(start, end);
+                    }
+                }
+            }
+
+            for ((scheduler, _), (start, confirmed)) in
+                schedulers.iter().zip(starts.into_iter().zip(confirmed))
+            {
+                prop_assert_eq!(scheduler.next_block(), std::cmp::max(start, confirmed + 1));
+                assert_no_gaps(scheduler, start.as_u64())?;
+            }
+        }
+
+        #[test]
+        fn block_ranges_match_a_set_of_blocks(
+            inserts in prop::collection::vec((0..200u64, 0..30u64), 0..20),
+            remove_from in prop::option::of(0..250u64),
+            (from, to) in (0..250u64, 0..250u64),
+        ) {
+            let mut ranges = BlockRanges::default();
+            let mut model = BTreeSet::new();
+            for (start, len) in inserts {
+                ranges.insert(U64::from(start), U64::from(start + len));
+                model.extend(start..=start + len);
+            }
+            if let Some(block) = remove_from {
+                ranges.remove_from(U64::from(block));
+                model.retain(|b| *b < block);
+            }
+
+            prop_assert_eq!(blocks(&ranges), model.clone());
+
+            // Ranges are disjoint and never adjacent
+            let pairs: Vec<(U64, U64)> = ranges.iter().collect();
+            for window in pairs.windows(2) {
+                prop_assert!(window[0].1 + 1 < window[1].0);
+            }
+
+            let gaps: BTreeSet<u64> = ranges
+                .gaps(U64::from(from), U64::from(to))
+                .into_iter()
+                .flat_map(|(start, end)| start.as_u64()..=end.as_u64())
+                .collect();
+            let expected: BTreeSet<u64> = (from..=to).filter(|b| !model.contains(b)).collect();
+            prop_assert_eq!(gaps, expected);
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use sha3::{Digest, Keccak256};
+use web3::ethabi::token::{LenientTokenizer, Tokenizer};
+use web3::ethabi::{encode, Contract as AbiContract, Event as AbiEvent, ParamType, Token};
+use web3::types::{Log, H256};
+
+use crate::{
+    BlobIndexSettledEvent, BridgeEvent, Confirmation, ContractEvent, EoEvent, EoServerError,
+    RangeScheduler,
+};
+
+/// Decodes a log of a registered event
+pub type EventHandler = fn(&Log, &AbiEvent) -> Result<EoEvent, EoServerError>;
+
+/// The topics `eth_getLogs` matches, position 0 being the event signature.
+/// `None` matches anything, otherwise any of the listed values.
+pub type LogTopics = [Option<Vec<H256>>; 4];
+
+/// An event the listener watches for, with its own topic, confirmation
+/// depth, scan progress and handler
+#[derive(Clone, Debug)]
+pub struct RegisteredEvent {
+    abi: AbiEvent,
+    topic: H256,
+    /// The values allowed for each indexed parameter, topics 1 to 3
+    indexed: [Option<Vec<H256>>; 3],
+    confirmation: Confirmation,
+    scheduler: RangeScheduler,
+    handler: EventHandler,
+}
+
+impl RegisteredEvent {
+    /// Watch `abi`, matching its logs by the hash of its signature.
+    ///
+    /// `Bridge` and `BlobIndexSettled` are decoded into their own `EoEvent`
+    /// variants, so their signature has to be the one those expect. Any
+    /// other event is decoded into an `EoEvent::Contract`.
+    pub fn new(abi: AbiEvent) -> Result<Self, EoServerError> {
+        if abi.anonymous {
+            return Err(abi_error(format!(
+                "{} is anonymous, its logs cannot be matched",
+                abi.name
+            )));
+        }
+        if let Some(expected) = expected_signature(&abi.name) {
+            check_signature(&abi, expected)?;
+        }
+
+        Ok(RegisteredEvent {
+            topic: abi.signature(),
+            handler: default_handler(&abi.name),
+            indexed: Default::default(),
+            abi,
+            confirmation: Confirmation::default(),
+            scheduler: RangeScheduler::default(),
+        })
+    }
+
+    /// Watch the event `event` in `abi`, see `from_abis`
+    pub fn from_abi(abi: &AbiContract, event: &str) -> Result<Self, EoServerError> {
+        RegisteredEvent::from_abis(std::slice::from_ref(abi), event)
+    }
+
+    /// Watch the event `event` from the first of `abis` that has it.
+    ///
+    /// `event` is either a name or a full signature like
+    /// `Transfer(address,address,uint256)`. A signature picks the overload
+    /// of the event with exactly those parameters and fails if there is
+    /// none, so a changed ABI is caught at startup instead of by logs that
+    /// silently stop matching.
+    pub fn from_abis(abis: &[AbiContract], event: &str) -> Result<Self, EoServerError> {
+        let event: String = event.chars().filter(|c| !c.is_whitespace()).collect();
+        let (name, signature) = match event.split_once('(') {
+            Some((name, _)) => (name, Some(event.as_str())),
+            None => (event.as_str(), None),
+        };
+
+        let overloads: Vec<&AbiEvent> = abis
+            .iter()
+            .filter_map(|abi| abi.events_by_name(name).ok())
+            .flatten()
+            .collect();
+        if overloads.is_empty() {
+            return Err(abi_error(format!("the ABI has no {} event", name)));
+        }
+
+        let abi = match signature {
+            Some(signature) => overloads
+                .iter()
+                .find(|abi| abi.signature() == signature_topic(signature))
+                .ok_or_else(|| {
+                    let found: Vec<String> =
+                        overloads.iter().map(|abi| event_signature(abi)).collect();
+                    abi_error(format!(
+                        "expected the {} event to be {}, but the ABI has {}",
+                        name,
+                        signature,
+                        found.join(", ")
+                    ))
+                })?,
+            None => overloads[0],
+        };
+
+        RegisteredEvent::new(abi.clone())
+    }
+
+    pub fn with_confirmation(mut self, confirmation: Confirmation) -> Self {
+        self.confirmation = confirmation;
+        self
+    }
+
+    pub fn with_scheduler(mut self, scheduler: RangeScheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn with_handler(mut self, handler: EventHandler) -> Self {
+        self.handler = handler;
+        self
+    }
+
+    /// Only match logs whose indexed parameter `param` is one of `values`.
+    /// The filter is part of the `eth_getLogs` topics, so the node does the
+    /// filtering. Calling it again for the same parameter replaces the
+    /// values.
+    pub fn with_indexed_filter(
+        mut self,
+        param: &str,
+        values: impl IntoIterator<Item = Token>,
+    ) -> Result<Self, EoServerError> {
+        let (position, kind) = self.indexed_param(param)?;
+        let topics = values
+            .into_iter()
+            .map(|value| indexed_topic(value, &kind))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|reason| {
+                abi_error(format!(
+                    "invalid filter on {}.{}: {}",
+                    self.abi.name, param, reason
+                ))
+            })?;
+
+        self.indexed[position] = Some(topics);
+        Ok(self)
+    }
+
+    /// Parse `value` as the type of the indexed parameter `param`, for
+    /// filters given as text
+    pub fn parse_indexed(&self, param: &str, value: &str) -> Result<Token, EoServerError> {
+        let (_, kind) = self.indexed_param(param)?;
+        LenientTokenizer::tokenize(&kind, value).map_err(|e| {
+            EoServerError::abi(
+                format!("invalid value {:?} for {}.{}", value, self.abi.name, param),
+                e,
+            )
+        })
+    }
+
+    /// The position among the indexed parameters and the type of `param`
+    fn indexed_param(&self, param: &str) -> Result<(usize, ParamType), EoServerError> {
+        self.abi
+            .inputs
+            .iter()
+            .filter(|input| input.indexed)
+            .enumerate()
+            .find(|(_, input)| input.name == param)
+            .map(|(position, input)| (position, input.kind.clone()))
+            .ok_or_else(|| {
+                abi_error(format!(
+                    "the {} event has no indexed parameter {}",
+                    self.abi.name, param
+                ))
+            })
+    }
+
+    /// The topics to pass to `eth_getLogs` for this event alone
+    pub fn log_topics(&self) -> LogTopics {
+        let [topic1, topic2, topic3] = self.indexed.clone();
+        [Some(vec![self.topic]), topic1, topic2, topic3]
+    }
+
+    /// Whether `log` was emitted for this event and passes its indexed
+    /// filters
+    pub fn matches(&self, log: &Log) -> bool {
+        log.topics.first() == Some(&self.topic)
+            && self
+                .indexed
+                .iter()
+                .enumerate()
+                .all(|(i, values)| match values {
+                    Some(values) => log
+                        .topics
+                        .get(i + 1)
+                        .is_some_and(|topic| values.contains(topic)),
+                    None => true,
+                })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.abi.name
+    }
+
+    pub fn abi(&self) -> &AbiEvent {
+        &self.abi
+    }
+
+    /// The hash of the event signature, the first topic of its logs
+    pub fn topic(&self) -> H256 {
+        self.topic
+    }
+
+    pub fn confirmation(&self) -> Confirmation {
+        self.confirmation
+    }
+
+    pub fn scheduler(&self) -> &RangeScheduler {
+        &self.scheduler
+    }
+
+    pub(crate) fn scheduler_mut(&mut self) -> &mut RangeScheduler {
+        &mut self.scheduler
+    }
+
+    pub fn decode(&self, log: &Log) -> Result<EoEvent, EoServerError> {
+        (self.handler)(log, &self.abi)
+    }
+}
+
+/// The canonical signature of an ABI event, like
+/// `Transfer(address,address,uint256)`
+pub fn event_signature(abi: &AbiEvent) -> String {
+    let params: Vec<String> = abi
+        .inputs
+        .iter()
+        .map(|param| param.kind.to_string())
+        .collect();
+    format!("{}({})", abi.name, params.join(","))
+}
+
+/// The first topic of logs emitted for the event with the canonical
+/// signature `signature`
+pub fn signature_topic(signature: &str) -> H256 {
+    H256::from_slice(&Keccak256::digest(signature.as_bytes()))
+}
+
+/// The signature the typed events are decoded with
+fn expected_signature(name: &str) -> Option<&'static str> {
+    match name {
+        BridgeEvent::NAME => Some(BridgeEvent::SIGNATURE),
+        BlobIndexSettledEvent::NAME => Some(BlobIndexSettledEvent::SIGNATURE),
+        _ => None,
+    }
+}
+
+fn check_signature(abi: &AbiEvent, expected: &str) -> Result<(), EoServerError> {
+    if abi.signature() == signature_topic(expected) {
+        return Ok(());
+    }
+
+    Err(abi_error(format!(
+        "expected the {} event to be {}, but the ABI has {}",
+        abi.name,
+        expected,
+        event_signature(abi)
+    )))
+}
+
+/// How the value of an indexed parameter appears in the topics of a log:
+/// value types as is, strings and bytes as the hash of their contents and
+/// arrays as the hash of their padded elements
+fn indexed_topic(value: Token, kind: &ParamType) -> Result<H256, String> {
+    if !value.type_check(kind) {
+        return Err(format!("{:?} is not a {}", value, kind));
+    }
+
+    let hashed = match value {
+        Token::String(s) => s.into_bytes(),
+        Token::Bytes(bytes) => bytes,
+        Token::Array(items) | Token::FixedArray(items)
+            if items.iter().all(|item| !item.is_dynamic()) =>
+        {
+            encode(&items)
+        }
+        Token::Array(_) | Token::FixedArray(_) | Token::Tuple(_) => {
+            return Err(format!("filtering on {} is not supported", kind));
+        }
+        value => return Ok(H256::from_slice(&encode(&[value]))),
+    };
+
+    Ok(H256::from_slice(&Keccak256::digest(hashed)))
+}
+
+fn abi_error(reason: String) -> EoServerError {
+    EoServerError::Abi {
+        reason,
+        source: None,
+    }
+}
+
+fn default_handler(name: &str) -> EventHandler {
+    match name {
+        BridgeEvent::NAME => |log, abi| BridgeEvent::from_log(log, abi).map(EoEvent::Bridge),
+        BlobIndexSettledEvent::NAME => {
+            |log, abi| BlobIndexSettledEvent::from_log(log, abi).map(EoEvent::Settlement)
+        }
+        _ => |log, abi| ContractEvent::from_log(log, abi).map(EoEvent::Contract),
+    }
+}
+
+/// The events an `EoServer` listens for, in the order they were registered
+#[derive(Clone, Debug, Default)]
+pub struct EventRegistry {
+    events: Vec<RegisteredEvent>,
+}
+
+impl EventRegistry {
+    pub fn new() -> Self {
+        EventRegistry::default()
+    }
+
+    /// Watch every one of `events` in `abis`, with the default confirmation
+    /// and scheduler, see `RegisteredEvent::from_abis`
+    pub fn from_abis<'a>(
+        abis: &[AbiContract],
+        events: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, EoServerError> {
+        let mut registry = EventRegistry::new();
+        for event in events {
+            registry.register(RegisteredEvent::from_abis(abis, event)?)?;
+        }
+        Ok(registry)
+    }
+
+    /// Add an event. Events are checkpointed by name, so two events may not
+    /// share one.
+    pub fn register(&mut self, event: RegisteredEvent) -> Result<&mut Self, EoServerError> {
+        if self.get(event.name()).is_some() {
+            return Err(EoServerError::Config(format!(
+                "the {} event is registered twice",
+                event.name()
+            )));
+        }
+
+        self.events.push(event);
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredEvent> {
+        self.events.iter().find(|event| event.name() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredEvent> + '_ {
+        self.events.iter()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut RegisteredEvent> + '_ {
+        self.events.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The topics of every event, to match any of them in topic position 0
+    pub fn topics(&self) -> Vec<H256> {
+        self.events.iter().map(RegisteredEvent::topic).collect()
+    }
+
+    /// The topics of one `eth_getLogs` call per set of events that share the
+    /// same indexed filters, which together match every registered event
+    pub fn log_topics(&self) -> Vec<LogTopics> {
+        let mut groups: Vec<LogTopics> = Vec::new();
+        for event in &self.events {
+            let [topic0, topic1, topic2, topic3] = event.log_topics();
+            let indexed = [topic1, topic2, topic3];
+            match groups.iter_mut().find(|group| group[1..] == indexed) {
+                Some(group) => group[0]
+                    .get_or_insert_with(Vec::new)
+                    .extend(topic0.unwrap_or_default()),
+                None => {
+                    let [topic1, topic2, topic3] = indexed;
+                    groups.push([topic0, topic1, topic2, topic3]);
+                }
+            }
+        }
+        groups
+    }
+
+    /// The index of the event a log was emitted for, `None` if it matches
+    /// no event or is filtered out by the indexed filters of its event
+    pub(crate) fn position(&self, log: &Log) -> Option<usize> {
+        self.events.iter().position(|event| event.matches(log))
+    }
+
+    pub(crate) fn at(&self, index: usize) -> &RegisteredEvent {
+        &self.events[index]
+    }
+
+    pub(crate) fn at_mut(&mut self, index: usize) -> &mut RegisteredEvent {
+        &mut self.events[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use web3::types::H160;
+
+    use super::*;
+    use crate::checkpoint::temp_path;
+    use crate::load_abi;
+
+    /// An ABI with the given events, each a name and its parameter types
+    fn abi(events: &[(&str, &[&str])]) -> AbiContract {
+        let events: Vec<serde_json::Value> = events
+            .iter()
+            .map(|(name, inputs)| {
+                let inputs: Vec<serde_json::Value> = inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, kind)| json!({"name": format!("arg{}", i), "type": kind, "indexed": false}))
+                    .collect();
+                json!({"type": "event", "name": name, "anonymous": false, "inputs": inputs})
+            })
+            .collect();
+        AbiContract::load(serde_json::to_vec(&events).unwrap().as_slice()).unwrap()
+    }
+
+    fn rejection(result: Result<RegisteredEvent, EoServerError>) -> String {
+        match result {
+            Err(err @ EoServerError::Abi { .. }) => err.to_string(),
+            Err(err) => panic!("expected an ABI error, got {}", err),
+            Ok(event) => panic!(
+                "expected an ABI error, got {}",
+                event_signature(event.abi())
+            ),
+        }
+    }
+
+    fn topic(hex: &str) -> H256 {
+        hex.parse().unwrap()
+    }
+
+    fn accounts() -> Token {
+        Token::Array(vec![
+            Token::Address(H160::repeat_byte(0x11)),
+            Token::Address(H160::repeat_byte(0x22)),
+        ])
+    }
+
+    /// keccak256 of the two addresses, each left padded to 32 bytes
+    const ACCOUNTS_TOPIC: &str =
+        "0x1bbe365357fe28ec15df954baa1b29fb309dd0e8a21208d768bce9ab1c0c4fd0";
+
+    #[test]
+    fn hashes_an_indexed_string() {
+        assert_eq!(
+            indexed_topic(Token::String("hello".to_string()), &ParamType::String),
+            Ok(topic(
+                "0x1c8aff950685c2ed4bc3174f3472287b56d9517b9c948127319a09a7a36deac8"
+            ))
+        );
+    }
+
+    #[test]
+    fn hashes_indexed_bytes() {
+        assert_eq!(
+            indexed_topic(
+                Token::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+                &ParamType::Bytes
+            ),
+            Ok(topic(
+                "0xd4fd4e189132273036449fc9e11198c739161b4c0116a9a2dccdfa1c492006f1"
+            ))
+        );
+    }
+
+    #[test]
+    fn hashes_an_indexed_address_array() {
+        let kind = ParamType::Array(Box::new(ParamType::Address));
+        assert_eq!(indexed_topic(accounts(), &kind), Ok(topic(ACCOUNTS_TOPIC)));
+    }
+
+    #[test]
+    fn rejects_a_value_of_the_wrong_type() {
+        let kind = ParamType::Array(Box::new(ParamType::Address));
+        assert!(indexed_topic(Token::String("hello".to_string()), &kind).is_err());
+    }
+
+    #[test]
+    fn filters_blob_index_settled_by_accounts() {
+        let abi = AbiContract::load(include_bytes!("../eo_contract_abi.json").as_slice()).unwrap();
+        let event = RegisteredEvent::from_abi(&abi, BlobIndexSettledEvent::NAME).unwrap();
+
+        // Parsed from text, as filters given in the environment are
+        let value = event
+            .parse_indexed(
+                "accounts",
+                "[0x1111111111111111111111111111111111111111,0x2222222222222222222222222222222222222222]",
+            )
+            .unwrap();
+        assert_eq!(value, accounts());
+
+        let event = event.with_indexed_filter("accounts", [value]).unwrap();
+        assert_eq!(
+            event.log_topics(),
+            [
+                Some(vec![topic(
+                    "0x5c7813536a7e7b7f95eaf9fcfa83da5ceec6c6237454ad0bcc625ff3794ff619"
+                )]),
+                Some(vec![topic(ACCOUNTS_TOPIC)]),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_bridge_event_with_another_signature() {
+        // An older Bridge without the bridge event ID
+        let abi = abi(&[(
+            BridgeEvent::NAME,
+            &["address", "address", "uint256", "uint256", "string"],
+        )]);
+
+        let err = rejection(RegisteredEvent::from_abi(&abi, BridgeEvent::NAME));
+        assert!(
+            err.contains(&format!(
+                "expected the Bridge event to be {}, but the ABI has Bridge(address,address,uint256,uint256,string)",
+                BridgeEvent::SIGNATURE
+            )),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn picks_the_overload_with_the_signature() {
+        let abi = abi(&[
+            ("Transfer", &["address", "uint256"]),
+            ("Transfer", &["address", "address", "uint256"]),
+        ]);
+
+        let event = RegisteredEvent::from_abi(&abi, "Transfer(address, address, uint256)").unwrap();
+        assert_eq!(
+            event_signature(event.abi()),
+            "Transfer(address,address,uint256)"
+        );
+        assert_eq!(
+            event.topic(),
+            signature_topic("Transfer(address,address,uint256)")
+        );
+
+        let err = rejection(RegisteredEvent::from_abi(&abi, "Transfer(uint256)"));
+        assert!(
+            err.contains("expected the Transfer event to be Transfer(uint256), but the ABI has Transfer(address,uint256), Transfer(address,address,uint256)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn names_a_missing_event() {
+        let transfer = abi(&[("Transfer", &["address", "uint256"])]);
+
+        for event in ["Withdraw", "Withdraw(uint256)"] {
+            let err = rejection(RegisteredEvent::from_abi(&transfer, event));
+            assert!(err.contains("the ABI has no Withdraw event"), "{}", err);
+        }
+
+        // Searched in every ABI, in order
+        let withdraw = abi(&[("Withdraw", &["uint256"])]);
+        let event = RegisteredEvent::from_abis(&[transfer, withdraw], "Withdraw").unwrap();
+        assert_eq!(event.name(), "Withdraw");
+    }
+
+    #[test]
+    fn loads_an_abi_file() {
+        let path = temp_path("abi.json");
+        std::fs::write(&path, include_bytes!("../eo_contract_abi.json")).unwrap();
+        let abi = load_abi(&path).unwrap();
+        assert!(RegisteredEvent::from_abi(&abi, BridgeEvent::NAME).is_ok());
+
+        std::fs::write(&path, b"not json").unwrap();
+        let err = load_abi(&path).unwrap_err();
+        assert!(err.to_string().contains("failed to parse"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+        let err = load_abi(&path).unwrap_err();
+        assert!(err.to_string().contains("failed to open"), "{}", err);
+    }
+}
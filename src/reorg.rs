@@ -0,0 +1,347 @@
+use web3::{
+    types::{Block, BlockId, BlockNumber, H256, U64},
+    Transport,
+};
+
+use crate::{EoEvent, EoServer, EoServerError};
+
+/// How many blocks behind the highest recorded block we keep hashes and
+/// delivered events for. Reorgs deeper than this cannot be rolled back.
+pub const DEFAULT_REORG_DEPTH: u64 = 128;
+
+impl<T: Transport> EoServer<T> {
+    /// Compare the block hashes we recorded against the canonical chain and
+    /// roll back to the fork point if they diverged.
+    ///
+    /// Only some blocks have a recorded hash, so the fork point is the newest
+    /// recorded block that is still canonical and every block after it is
+    /// scanned again. A recorded block the node does not know (yet) says
+    /// nothing about a reorg, the check is then left for the next tick.
+    ///
+    /// Returns an `EoEvent::Removed` for every previously delivered event that
+    /// is no longer part of the canonical chain, newest first.
+    pub(crate) async fn check_reorg(&mut self) -> Result<Vec<EoEvent>, EoServerError> {
+        let recorded: Vec<(U64, H256)> = self
+            .block_hashes
+            .iter()
+            .rev()
+            .map(|(number, hash)| (*number, *hash))
+            .collect();
+
+        let mut diverged = None;
+        let mut fork_point = None;
+        for (number, hash) in recorded {
+            let canonical = self
+                .canonical_block(BlockNumber::Number(number))
+                .await?
+                .and_then(|block| block.hash);
+            match canonical {
+                Some(canonical) if canonical == hash => {
+                    fork_point = Some(number);
+                    break;
+                }
+                Some(_) => diverged = Some(number),
+                None => {
+                    log::debug!(
+                        "node does not know block {} yet, checking for reorgs later",
+                        number
+                    );
+                    return Ok(Vec::new());
+                }
+            }
+        }
+
+        // With no recorded block left on the canonical chain, roll back to
+        // just before the oldest one
+        let removed = match (diverged, fork_point) {
+            (Some(_), Some(fork_point)) => self.rewind_to(fork_point),
+            (Some(oldest), None) => self.rewind_to(oldest.saturating_sub(U64::from(1))),
+            (None, _) => Vec::new(),
+        };
+
+        // The head block also refreshes the cached head and the block time
+        if let Some(block) = self.canonical_block(BlockNumber::Latest).await? {
+            if let (Some(number), Some(hash)) = (block.number, block.hash) {
+                self.record_block_hash(number, hash);
+                self.record_head_block(number, block.timestamp.low_u64());
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Forget everything after `fork_point` and rewind the range schedulers
+    /// so the blocks after it are scanned again
+    pub(crate) fn rewind_to(&mut self, fork_point: U64) -> Vec<EoEvent> {
+        log::warn!(
+            "chain reorganization detected, rolling back to block {}",
+            fork_point
+        );
+
+        let next_block = fork_point + U64::from(1);
+        self.block_hashes.split_off(&next_block);
+        self.delivered.remove_from(next_block);
+        for event in self.registry.iter_mut() {
+            event.scheduler_mut().rewind(next_block);
+        }
+
+        if let Some((block, _)) = self.subscription_cursor {
+            if block > fork_point {
+                self.subscription_cursor = Some((fork_point, web3::types::U256::MAX));
+            }
+        }
+
+        self.recent_events
+            .split_off(&next_block)
+            .into_values()
+            .rev()
+            .flat_map(|events| events.into_iter().rev())
+            .map(|event| EoEvent::Removed(Box::new(event)))
+            .collect()
+    }
+
+    /// Remember a delivered event and the hash of the block it came from.
+    /// Returns `false` without recording anything if the event was delivered
+    /// before.
+    pub(crate) fn record_event(&mut self, event: &EoEvent) -> bool {
+        if !self.delivered.insert(event.block_number(), event.id()) {
+            return false;
+        }
+
+        self.record_block_hash(event.block_number(), event.block_hash());
+        self.recent_events
+            .entry(event.block_number())
+            .or_default()
+            .push(event.clone());
+        true
+    }
+
+    /// Turn a log the node flagged as `removed` into a retraction of the
+    /// event we delivered for it
+    pub(crate) fn retract_event(&mut self, event: EoEvent) -> EoEvent {
+        if let Some(events) = self.recent_events.get_mut(&event.block_number()) {
+            events.retain(|e| (e.tx_hash(), e.log_index()) != (event.tx_hash(), event.log_index()));
+        }
+        self.delivered.remove(event.block_number(), &event.id());
+
+        EoEvent::Removed(Box::new(event))
+    }
+
+    fn record_block_hash(&mut self, number: U64, hash: H256) {
+        self.block_hashes.insert(number, hash);
+
+        let highest = self.block_hashes.keys().last().copied().unwrap_or_default();
+        let oldest_kept = highest.saturating_sub(U64::from(self.reorg_depth));
+        self.block_hashes = self.block_hashes.split_off(&oldest_kept);
+        self.recent_events = self.recent_events.split_off(&oldest_kept);
+        self.delivered.prune_before(oldest_kept);
+    }
+
+    async fn canonical_block(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Option<Block<H256>>, EoServerError> {
+        self.rpc("eth_getBlockByNumber", || {
+            self.web3.eth().block(BlockId::Number(block))
+        })
+        .await
+        .map_err(|e| EoServerError::rpc(format!("failed to get block {:?}", block), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use web3::types::U256;
+
+    use super::*;
+    use crate::mock::{server_builder, MockTransport};
+    use crate::BridgeEvent;
+
+    /// Block hashes of the canonical chain by number
+    type Chain = Arc<Mutex<BTreeMap<u64, H256>>>;
+
+    /// The hashes of `numbers` on fork `fork`
+    fn blocks(numbers: impl IntoIterator<Item = u64>, fork: u64) -> BTreeMap<u64, H256> {
+        numbers
+            .into_iter()
+            .map(|number| (number, H256::from_low_u64_be(fork * 1_000 + number)))
+            .collect()
+    }
+
+    /// A node that answers `eth_getBlockByNumber` from `chain`
+    fn node(chain: Chain) -> MockTransport {
+        MockTransport::new(move |method, params| {
+            assert_eq!(method, "eth_getBlockByNumber");
+            let chain = chain.lock().unwrap();
+            let number = match params[0].as_str().unwrap() {
+                "latest" => chain.keys().last().copied(),
+                number => Some(u64::from_str_radix(&number[2..], 16).unwrap()),
+            };
+            let Some((number, hash)) = number.and_then(|n| chain.get_key_value(&n)) else {
+                return Ok(Value::Null);
+            };
+
+            let block = Block::<H256> {
+                number: Some(U64::from(*number)),
+                hash: Some(*hash),
+                timestamp: U256::from(number * 12),
+                ..Default::default()
+            };
+            Ok(serde_json::to_value(block).unwrap())
+        })
+    }
+
+    fn server(chain: &Chain, reorg_depth: u64) -> EoServer<MockTransport> {
+        let mut server = server_builder(node(chain.clone()))
+            .reorg_depth(reorg_depth)
+            .build()
+            .unwrap();
+        for event in server.registry.iter_mut() {
+            event.scheduler_mut().complete(U64::zero(), U64::from(10));
+        }
+        server
+    }
+
+    /// A Bridge event from the canonical block `block` of `chain`
+    fn bridge(chain: &Chain, block: u64, log_index: u64) -> EoEvent {
+        EoEvent::Bridge(BridgeEvent {
+            user: Default::default(),
+            token_address: Default::default(),
+            amount: U256::zero(),
+            token_id: U256::zero(),
+            token_type: "erc20".to_string(),
+            bridge_event_id: U256::from(log_index),
+            block_hash: chain.lock().unwrap()[&block],
+            tx_hash: H256::from_low_u64_be(block),
+            log_index: U256::from(log_index),
+            block_number: U64::from(block),
+        })
+    }
+
+    fn removed(event: &EoEvent) -> EoEvent {
+        EoEvent::Removed(Box::new(event.clone()))
+    }
+
+    #[tokio::test]
+    async fn rolls_back_to_the_newest_canonical_block() {
+        let chain: Chain = Arc::new(Mutex::new(blocks(0..=10, 0)));
+        let mut server = server(&chain, DEFAULT_REORG_DEPTH);
+        let events = [
+            bridge(&chain, 5, 0),
+            bridge(&chain, 7, 0),
+            bridge(&chain, 7, 1),
+            bridge(&chain, 9, 0),
+        ];
+        for event in &events {
+            assert!(server.record_event(event));
+        }
+        assert!(server.check_reorg().await.unwrap().is_empty());
+
+        // Blocks 7 onwards are replaced by a longer fork
+        chain.lock().unwrap().extend(blocks(7..=11, 1));
+
+        let retracted = server.check_reorg().await.unwrap();
+        assert_eq!(
+            retracted,
+            vec![
+                removed(&events[3]),
+                removed(&events[2]),
+                removed(&events[1])
+            ]
+        );
+
+        // Block 5 is the newest recorded block still on the chain
+        for event in server.registry.iter() {
+            assert_eq!(event.scheduler().next_block(), U64::from(6));
+        }
+        assert_eq!(
+            server.block_hashes.keys().copied().collect::<Vec<_>>(),
+            vec![U64::from(5), U64::from(11)]
+        );
+        assert_eq!(server.delivered.len(), 1);
+        assert!(server.delivered.contains(U64::from(5), &events[0].id()));
+        assert_eq!(
+            server.recent_events.keys().copied().collect::<Vec<_>>(),
+            vec![U64::from(5)]
+        );
+
+        // The rollback is only reported once
+        assert!(server.check_reorg().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rolls_back_before_the_oldest_block_when_none_is_canonical() {
+        let chain: Chain = Arc::new(Mutex::new(blocks(0..=10, 0)));
+        let mut server = server(&chain, DEFAULT_REORG_DEPTH);
+        let events = [bridge(&chain, 8, 0), bridge(&chain, 9, 0)];
+        for event in &events {
+            server.record_event(event);
+        }
+
+        chain.lock().unwrap().extend(blocks(4..=10, 1));
+
+        let retracted = server.check_reorg().await.unwrap();
+        assert_eq!(retracted, vec![removed(&events[1]), removed(&events[0])]);
+        for event in server.registry.iter() {
+            assert_eq!(event.scheduler().next_block(), U64::from(8));
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_blocks_the_node_does_not_know() {
+        let chain: Chain = Arc::new(Mutex::new(blocks(0..=10, 0)));
+        let mut server = server(&chain, DEFAULT_REORG_DEPTH);
+        server.record_event(&bridge(&chain, 9, 0));
+        // Recorded from a node that is ahead of this one
+        server.record_block_hash(U64::from(12), H256::repeat_byte(0xff));
+
+        assert!(server.check_reorg().await.unwrap().is_empty());
+        for event in server.registry.iter() {
+            assert_eq!(event.scheduler().next_block(), U64::from(11));
+        }
+        assert_eq!(server.recent_events.len(), 1);
+    }
+
+    #[test]
+    fn retracts_a_removed_log() {
+        let chain: Chain = Arc::new(Mutex::new(blocks(0..=10, 0)));
+        let mut server = server(&chain, DEFAULT_REORG_DEPTH);
+        let kept = bridge(&chain, 7, 0);
+        let event = bridge(&chain, 7, 1);
+        server.record_event(&kept);
+        server.record_event(&event);
+
+        assert_eq!(server.retract_event(event.clone()), removed(&event));
+        assert_eq!(server.recent_events[&U64::from(7)], vec![kept]);
+        assert!(!server.delivered.contains(U64::from(7), &event.id()));
+
+        // Included again, it is delivered again
+        assert!(server.record_event(&event));
+    }
+
+    #[test]
+    fn keeps_history_for_the_reorg_depth_only() {
+        let chain: Chain = Arc::new(Mutex::new(blocks(0..=30, 0)));
+        let mut server = server(&chain, 10);
+        let old = bridge(&chain, 1, 0);
+        let recent = bridge(&chain, 25, 0);
+        assert!(server.record_event(&old));
+        assert!(server.record_event(&recent));
+        assert!(!server.record_event(&recent));
+
+        assert_eq!(
+            server.block_hashes.keys().copied().collect::<Vec<_>>(),
+            vec![U64::from(25)]
+        );
+        assert_eq!(
+            server.recent_events.keys().copied().collect::<Vec<_>>(),
+            vec![U64::from(25)]
+        );
+        assert_eq!(server.delivered.len(), 1);
+        assert!(!server.delivered.contains(U64::from(1), &old.id()));
+    }
+}
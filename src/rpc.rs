@@ -0,0 +1,384 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use web3::{error::TransportError, Transport};
+
+use crate::{is_provider_limit_error, shutdown::Shutdown, EoServer, EoServerError};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How RPC calls that fail with a transient error are retried
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a call is made before giving up, including the first
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled after every attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before retry number `retry` (starting at 1), with full
+    /// jitter so that listeners sharing a provider do not retry in lockstep
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Stops calling a provider that keeps failing.
+///
+/// After `failure_threshold` calls in a row have failed with a transient
+/// error, even after retrying, the breaker opens and the listener pauses for
+/// `cooldown`. The next call after that is let through; the breaker closes
+/// again if it succeeds and reopens if it fails. Clones share their state.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_COOLDOWN)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Arc::default(),
+        }
+    }
+
+    /// How long until calls are let through again, `None` if they are now
+    pub fn open_for(&self) -> Option<Duration> {
+        let open_until = self.lock().open_until?;
+        open_until.checked_duration_since(Instant::now())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_for().is_some()
+    }
+
+    fn record_success(&self) {
+        let mut state = self.lock();
+        if state.open_until.take().is_some() {
+            log::info!("provider recovered, circuit breaker closed");
+        }
+        state.consecutive_failures = 0;
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.lock();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.failure_threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            log::error!(
+                "provider failed {} calls in a row, circuit breaker open: pausing for {:?}",
+                state.consecutive_failures,
+                self.cooldown
+            );
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Errors that tell whether the call that returned them is worth retrying
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for web3::Error {
+    fn is_retryable(&self) -> bool {
+        is_transient_rpc_error(self)
+    }
+}
+
+impl Retryable for web3::contract::Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            web3::contract::Error::Api(err) => is_transient_rpc_error(err),
+            _ => false,
+        }
+    }
+}
+
+impl Retryable for EoServerError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            EoServerError::Rpc { source, .. } => is_transient_rpc_error(source),
+            EoServerError::InvalidResponse(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the provider rejected a call because too many were made, which
+/// waiting out fixes and smaller ranges do not
+pub fn is_rate_limit_error(err: &web3::Error) -> bool {
+    const RATE_LIMIT_MESSAGES: [&str; 4] = [
+        "rate limit",
+        "rate exceeded",
+        "too many requests",
+        "requests per second",
+    ];
+
+    let message = match err {
+        web3::Error::Transport(TransportError::Code(429)) => return true,
+        web3::Error::Rpc(rpc) => rpc.message.to_lowercase(),
+        other => other.to_string().to_lowercase(),
+    };
+
+    RATE_LIMIT_MESSAGES.iter().any(|m| message.contains(m))
+}
+
+/// Whether an RPC call failed for a reason that may go away on its own: the
+/// connection, rate limiting, or an overloaded node. Ranges the provider
+/// rejected as too large are not, they are retried in smaller chunks instead.
+pub fn is_transient_rpc_error(err: &web3::Error) -> bool {
+    const TRANSIENT_MESSAGES: [&str; 4] = [
+        "timeout",
+        "timed out",
+        "temporarily unavailable",
+        "header not found",
+    ];
+
+    if is_rate_limit_error(err) {
+        return true;
+    }
+    if is_provider_limit_error(err) {
+        return false;
+    }
+
+    match err {
+        web3::Error::Unreachable | web3::Error::Io(_) | web3::Error::InvalidResponse(_) => true,
+        web3::Error::Transport(TransportError::Code(code)) => *code >= 500,
+        web3::Error::Transport(TransportError::Message(_)) => true,
+        web3::Error::Rpc(rpc) => {
+            // -32603 is an internal error and -32000 to -32099 are server
+            // errors, which nodes return when they are overloaded
+            let code = rpc.code.code();
+            let message = rpc.message.to_lowercase();
+            code == -32603
+                || ((-32099..=-32000).contains(&code)
+                    && TRANSIENT_MESSAGES.iter().any(|m| message.contains(m)))
+        }
+        _ => false,
+    }
+}
+
+impl<T: Transport> EoServer<T> {
+    /// Make an RPC call, retrying transient failures under the retry policy
+    /// and reporting the outcome to the circuit breaker
+    pub(crate) async fn rpc<R, E, F, Fut>(&self, method: &str, mut call: F) -> Result<R, E>
+    where
+        E: Retryable + std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Ok(result) => {
+                    self.circuit_breaker.record_success();
+                    return Ok(result);
+                }
+                Err(err) if !err.is_retryable() => return Err(err),
+                Err(err) if attempt >= self.retry_policy.max_attempts => {
+                    log::warn!("{} failed after {} attempts: {}", method, attempt, err);
+                    self.circuit_breaker.record_failure();
+                    return Err(err);
+                }
+                Err(err) => {
+                    let backoff = self.retry_policy.backoff(attempt);
+                    log::warn!(
+                        "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                        method,
+                        attempt,
+                        self.retry_policy.max_attempts,
+                        backoff,
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Pause while the circuit breaker is open, returning `true` if a
+    /// `StopToken` arrived in the meantime
+    pub(crate) async fn wait_for_circuit(&self, shutdown: &mut Shutdown) -> bool {
+        match self.circuit_breaker.open_for() {
+            Some(pause) => {
+                log::error!("circuit breaker open, pausing for {:?}", pause);
+                shutdown.sleep(pause).await
+            }
+            None => false,
+        }
+    }
+
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::rpc_error;
+
+    #[test]
+    fn retries_rate_limits() {
+        for err in [
+            rpc_error(-32005, "project ID request rate exceeded"),
+            rpc_error(-32000, "rate limit exceeded"),
+            rpc_error(-32029, "Too Many Requests"),
+            web3::Error::Transport(TransportError::Code(429)),
+        ] {
+            assert!(!is_provider_limit_error(&err), "{}", err);
+            assert!(is_transient_rpc_error(&err), "{}", err);
+        }
+    }
+
+    #[test]
+    fn splits_ranges_the_provider_rejects() {
+        for err in [
+            rpc_error(-32005, "query returned more than 10000 results"),
+            rpc_error(-32602, "eth_getLogs is limited to a 10000 range"),
+            rpc_error(-32000, "block range too large"),
+            rpc_error(-32000, "Log response size exceeded"),
+        ] {
+            assert!(is_provider_limit_error(&err), "{}", err);
+            assert!(!is_transient_rpc_error(&err), "{}", err);
+        }
+
+        // The code alone does not say what limit was hit
+        assert!(!is_provider_limit_error(&rpc_error(
+            -32005,
+            "limit exceeded"
+        )));
+    }
+
+    #[test]
+    fn classifies_other_errors() {
+        assert!(is_transient_rpc_error(&web3::Error::Unreachable));
+        assert!(is_transient_rpc_error(&web3::Error::Transport(
+            TransportError::Code(503)
+        )));
+        assert!(is_transient_rpc_error(&rpc_error(-32603, "internal error")));
+        assert!(is_transient_rpc_error(&rpc_error(
+            -32000,
+            "header not found"
+        )));
+
+        assert!(!is_transient_rpc_error(&web3::Error::Transport(
+            TransportError::Code(401)
+        )));
+        assert!(!is_transient_rpc_error(&rpc_error(
+            -32000,
+            "execution reverted"
+        )));
+        assert!(!is_transient_rpc_error(&rpc_error(
+            -32601,
+            "method not found"
+        )));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for (retry, ceiling) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            for _ in 0..20 {
+                assert!(policy.backoff(retry) <= Duration::from_millis(ceiling));
+            }
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_and_closes_on_success() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(breaker.open_for().unwrap() <= Duration::from_secs(60));
+        // Clones share the state
+        assert!(breaker.clone().is_open());
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn lets_calls_through_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(breaker.open_for(), None);
+
+        // The trial call fails, so the breaker reopens
+        breaker.record_failure();
+        assert!(breaker.is_open());
+    }
+}
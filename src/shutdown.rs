@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use tokio::sync::oneshot::Receiver;
+
+use crate::StopToken;
+
+/// Listens for the `StopToken` that asks a running `EoServer` to stop.
+///
+/// Dropping the sender without sending a token does not stop the server.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    receiver: Option<Receiver<StopToken>>,
+    stopped: bool,
+}
+
+impl Shutdown {
+    pub(crate) fn new(receiver: Receiver<StopToken>) -> Self {
+        Shutdown {
+            receiver: Some(receiver),
+            stopped: false,
+        }
+    }
+
+    /// Whether a `StopToken` has been received, without waiting for one
+    pub(crate) fn is_stopped(&mut self) -> bool {
+        if let Some(receiver) = self.receiver.as_mut() {
+            match receiver.try_recv() {
+                Ok(StopToken) => self.stop(),
+                Err(tokio::sync::oneshot::error::TryRecvError::Closed) => self.receiver = None,
+                Err(tokio::sync::oneshot::error::TryRecvError::Empty) => {}
+            }
+        }
+
+        self.stopped
+    }
+
+    /// Resolve once a `StopToken` has been received
+    pub(crate) async fn wait(&mut self) {
+        if self.stopped {
+            return;
+        }
+
+        let Some(receiver) = self.receiver.as_mut() else {
+            return futures::future::pending().await;
+        };
+
+        match receiver.await {
+            Ok(StopToken) => self.stop(),
+            Err(_) => {
+                self.receiver = None;
+                futures::future::pending().await
+            }
+        }
+    }
+
+    /// Sleep for `duration`, returning `true` early if a `StopToken` arrives
+    pub(crate) async fn sleep(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = self.wait() => true,
+            _ = tokio::time::sleep(duration) => false,
+        }
+    }
+
+    fn stop(&mut self) {
+        self.stopped = true;
+        self.receiver = None;
+    }
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use web3::{
+    types::{Address, BlockId, BlockNumber, U64},
+    Transport,
+};
+
+use crate::{EoServer, EoServerError};
+
+/// Where a listener without a checkpoint starts scanning
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StartBlock {
+    /// Start at this block number
+    Block(U64),
+    /// Start at the block the contract was deployed in, found by binary
+    /// searching `eth_getCode`. Needs a node that serves historical state.
+    Deployment,
+    /// Start at the current head and only tail new blocks
+    Latest,
+    /// Start at the first block mined at or after this unix timestamp
+    Timestamp(u64),
+}
+
+impl std::str::FromStr for StartBlock {
+    type Err = EoServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_lowercase();
+        let invalid = || {
+            EoServerError::Config(format!(
+                "Invalid start block {:?}, expected a block number, \"deployment\", \"latest\" or \"timestamp:<unix seconds>\"",
+                s
+            ))
+        };
+
+        match value.as_str() {
+            "deployment" => Ok(StartBlock::Deployment),
+            "latest" => Ok(StartBlock::Latest),
+            _ => match value.strip_prefix("timestamp:") {
+                Some(timestamp) => timestamp
+                    .trim()
+                    .parse()
+                    .map(StartBlock::Timestamp)
+                    .map_err(|_| invalid()),
+                None => value
+                    .parse::<u64>()
+                    .map(|block| StartBlock::Block(U64::from(block)))
+                    .map_err(|_| invalid()),
+            },
+        }
+    }
+}
+
+impl<T: Transport> EoServer<T> {
+    /// The block number `start` refers to on the current chain
+    pub(crate) async fn resolve_start_block(
+        &mut self,
+        start: StartBlock,
+    ) -> Result<U64, EoServerError> {
+        match start {
+            StartBlock::Block(block) => Ok(block),
+            StartBlock::Deployment => {
+                let head = self.head().await?;
+                self.deployment_block(self.eo_address.parse()?, head).await
+            }
+            StartBlock::Latest => self.head().await,
+            StartBlock::Timestamp(timestamp) => {
+                let head = self.head().await?;
+                self.first_block_since(timestamp, head).await
+            }
+        }
+    }
+
+    /// The lowest block at which `address` has code
+    async fn deployment_block(&self, address: Address, head: U64) -> Result<U64, EoServerError> {
+        if !self.has_code(address, head).await? {
+            return Err(EoServerError::Config(format!(
+                "no contract is deployed at {:?}",
+                address
+            )));
+        }
+
+        // Once deployed the code stays, so the blocks with code are the tail
+        // of the chain
+        let (mut low, mut high) = (U64::zero(), head);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.has_code(address, mid).await? {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        Ok(low)
+    }
+
+    /// The first block mined at or after `timestamp`, or the block after
+    /// `head` if none has been yet
+    async fn first_block_since(&self, timestamp: u64, head: U64) -> Result<U64, EoServerError> {
+        if self.block_timestamp(head).await? < timestamp {
+            return Ok(head + 1);
+        }
+
+        let (mut low, mut high) = (U64::zero(), head);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.block_timestamp(mid).await? >= timestamp {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        Ok(low)
+    }
+
+    async fn has_code(&self, address: Address, block: U64) -> Result<bool, EoServerError> {
+        let code = self
+            .rpc("eth_getCode", || {
+                self.web3
+                    .eth()
+                    .code(address, Some(BlockNumber::Number(block)))
+            })
+            .await
+            .map_err(|e| {
+                EoServerError::rpc(format!("failed to get the code at block {}", block), e)
+            })?;
+        Ok(!code.0.is_empty())
+    }
+
+    async fn block_timestamp(&self, block: U64) -> Result<u64, EoServerError> {
+        self.rpc("eth_getBlockByNumber", || {
+            self.web3
+                .eth()
+                .block(BlockId::Number(BlockNumber::Number(block)))
+        })
+        .await
+        .map_err(|e| EoServerError::rpc(format!("failed to get block {}", block), e))?
+        .map(|block| block.timestamp.low_u64())
+        .ok_or_else(|| {
+            EoServerError::InvalidResponse(format!("node did not return block {}", block))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use web3::types::{Block, Bytes, H256, U256};
+
+    use super::*;
+    use crate::checkpoint::checkpoint;
+    use crate::mock::{server_builder, MockTransport};
+    use crate::{BlobIndexSettledEvent, BridgeEvent, MemoryCheckpointStore};
+
+    /// A node at `head` where the contract has code from block `deployed`
+    /// on, and block `n` was mined at `1_000 + 12 * n`
+    fn node(head: u64, deployed: u64) -> MockTransport {
+        MockTransport::new(move |method, params| {
+            let block = |param: &Value| u64::from_str_radix(&param.as_str().unwrap()[2..], 16);
+            match method {
+                "eth_blockNumber" => Ok(json!(format!("{:#x}", head))),
+                "eth_getCode" => {
+                    let code = match block(&params[1]).unwrap() >= deployed {
+                        true => vec![0x60, 0x80],
+                        false => Vec::new(),
+                    };
+                    Ok(serde_json::to_value(Bytes(code)).unwrap())
+                }
+                "eth_getBlockByNumber" => {
+                    let number = block(&params[0]).unwrap();
+                    assert!(number <= head, "block {} is not mined yet", number);
+                    let block = Block::<H256> {
+                        number: Some(U64::from(number)),
+                        timestamp: U256::from(1_000 + 12 * number),
+                        ..Default::default()
+                    };
+                    Ok(serde_json::to_value(block).unwrap())
+                }
+                _ => Err(web3::Error::Unreachable),
+            }
+        })
+    }
+
+    fn server(head: u64, deployed: u64) -> EoServer<MockTransport> {
+        server_builder(node(head, deployed)).build().unwrap()
+    }
+
+    #[test]
+    fn parses_start_blocks() {
+        for (value, start) in [
+            ("deployment", StartBlock::Deployment),
+            (" Latest ", StartBlock::Latest),
+            (
+                "timestamp: 1700000000",
+                StartBlock::Timestamp(1_700_000_000),
+            ),
+            ("123", StartBlock::Block(U64::from(123))),
+        ] {
+            assert_eq!(value.parse::<StartBlock>().unwrap(), start);
+        }
+
+        for value in ["", "-1", "0x10", "timestamp:", "timestamp:soon", "genesis"] {
+            let err = value.parse::<StartBlock>().unwrap_err();
+            assert!(err.to_string().contains("Invalid start block"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_the_deployment_block() {
+        for deployed in [0, 1, 37, 99, 100] {
+            let mut server = server(100, deployed);
+            let block = server
+                .resolve_start_block(StartBlock::Deployment)
+                .await
+                .unwrap();
+            assert_eq!(block, U64::from(deployed));
+        }
+
+        let err = server(100, 101)
+            .resolve_start_block(StartBlock::Deployment)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EoServerError::Config(_)));
+        assert!(
+            err.to_string().contains("no contract is deployed"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_the_first_block_since_a_timestamp() {
+        for (timestamp, expected) in [
+            (0, 0),
+            (1_000, 0),
+            (1_001, 1),
+            (1_012, 1),
+            (1_013, 2),
+            (2_188, 99),
+            (2_200, 100),
+            // Nothing has been mined since, so start with the next block
+            (2_201, 101),
+        ] {
+            let block = server(100, 0)
+                .resolve_start_block(StartBlock::Timestamp(timestamp))
+                .await
+                .unwrap();
+            assert_eq!(block, U64::from(expected), "timestamp {}", timestamp);
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_the_start_block_only_without_a_checkpoint() {
+        let mut saved = checkpoint(100);
+        saved.events.get_mut(BridgeEvent::NAME).unwrap().start_block = U64::from(40);
+        let mut server = server_builder(node(1_000, 0))
+            .checkpoint_store(MemoryCheckpointStore::with_checkpoint(saved))
+            .start_block(Some(StartBlock::Block(U64::from(500))))
+            .build()
+            .unwrap();
+        server.load_processed_blocks().await.unwrap();
+
+        let bridge = server.registry.get(BridgeEvent::NAME).unwrap().scheduler();
+        assert_eq!(bridge.start_block(), U64::from(40));
+        assert_eq!(bridge.next_block(), U64::from(101));
+        let settled = server
+            .registry
+            .get(BlobIndexSettledEvent::NAME)
+            .unwrap()
+            .scheduler();
+        assert_eq!(settled.start_block(), U64::from(500));
+        assert_eq!(settled.next_block(), U64::from(500));
+    }
+
+    #[tokio::test]
+    async fn resolves_the_start_block_only_when_needed() {
+        let mut saved = checkpoint(100);
+        saved.events.insert(
+            BlobIndexSettledEvent::NAME.to_string(),
+            saved.events[BridgeEvent::NAME].clone(),
+        );
+        // Resolving the start block would fail on this node
+        let node = MockTransport::new(|_, _| Err(web3::Error::Unreachable));
+        let mut server = server_builder(node.clone())
+            .checkpoint_store(MemoryCheckpointStore::with_checkpoint(saved))
+            .start_block(Some(StartBlock::Latest))
+            .build()
+            .unwrap();
+
+        server.load_processed_blocks().await.unwrap();
+        assert_eq!(node.calls("eth_blockNumber"), 0);
+    }
+}
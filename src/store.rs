@@ -0,0 +1,271 @@
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{Checkpoint, EoServerError};
+
+/// Where an `EoServer` persists its `Checkpoint`
+pub trait CheckpointStore: Debug + Send + Sync {
+    /// The last saved checkpoint, or `None` if nothing has been saved yet
+    fn load(&self) -> Result<Option<Checkpoint>, EoServerError>;
+
+    /// Replace the saved checkpoint, either entirely or not at all
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), EoServerError>;
+}
+
+/// Stores the checkpoint in a single file, see `Checkpoint::write_to`
+#[derive(Clone, Debug)]
+pub struct FileCheckpointStore {
+    path: PathBuf,
+}
+
+impl FileCheckpointStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileCheckpointStore { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&self) -> Result<Option<Checkpoint>, EoServerError> {
+        Checkpoint::read_from(&self.path)
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), EoServerError> {
+        checkpoint.write_to(&self.path)
+    }
+}
+
+/// Keeps the checkpoint in memory. Clones share the same checkpoint, so a
+/// test can hand one to the server and inspect what it saved through another.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryCheckpointStore {
+    checkpoint: Arc<Mutex<Option<Checkpoint>>>,
+}
+
+impl MemoryCheckpointStore {
+    pub fn new() -> Self {
+        MemoryCheckpointStore::default()
+    }
+
+    /// Start from an existing checkpoint, as if it had been saved before
+    pub fn with_checkpoint(checkpoint: Checkpoint) -> Self {
+        MemoryCheckpointStore {
+            checkpoint: Arc::new(Mutex::new(Some(checkpoint))),
+        }
+    }
+
+    /// The last saved checkpoint
+    pub fn get(&self) -> Option<Checkpoint> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Checkpoint>> {
+        // A panic while holding the lock cannot leave a half-written
+        // checkpoint behind, so a poisoned lock is still usable
+        self.checkpoint
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CheckpointStore for MemoryCheckpointStore {
+    fn load(&self) -> Result<Option<Checkpoint>, EoServerError> {
+        Ok(self.get())
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), EoServerError> {
+        *self.lock() = Some(checkpoint.clone());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteCheckpointStore, DEFAULT_BUSY_TIMEOUT};
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rusqlite::{Connection, OptionalExtension};
+
+    use super::CheckpointStore;
+    use crate::{Checkpoint, EoServerError};
+
+    /// How long a save waits for another listener's write to the same
+    /// database to finish
+    pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stores checkpoints in an embedded SQLite database, one row per
+    /// listener, so several listeners can share one durable store.
+    ///
+    /// Rows hold the same checksummed encoding as checkpoint files.
+    #[derive(Debug)]
+    pub struct SqliteCheckpointStore {
+        conn: Mutex<Connection>,
+        listener: String,
+    }
+
+    impl SqliteCheckpointStore {
+        /// Open (or create) the database at `path` and store the checkpoint
+        /// of `listener` in it
+        pub fn open(
+            path: impl AsRef<Path>,
+            listener: impl Into<String>,
+        ) -> Result<Self, rusqlite::Error> {
+            Self::from_connection(Connection::open(path)?, listener)
+        }
+
+        /// A database that only lives as long as the store
+        pub fn open_in_memory(listener: impl Into<String>) -> Result<Self, rusqlite::Error> {
+            Self::from_connection(Connection::open_in_memory()?, listener)
+        }
+
+        /// Use an open connection. Writers wait up to `DEFAULT_BUSY_TIMEOUT`
+        /// for each other, and file databases switch to WAL mode so readers
+        /// never block them.
+        pub fn from_connection(
+            conn: Connection,
+            listener: impl Into<String>,
+        ) -> Result<Self, rusqlite::Error> {
+            conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+            // In-memory databases answer "memory" and stay as they are
+            conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS eo_checkpoints (
+                    listener   TEXT PRIMARY KEY,
+                    checkpoint BLOB NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+            )?;
+
+            Ok(SqliteCheckpointStore {
+                conn: Mutex::new(conn),
+                listener: listener.into(),
+            })
+        }
+
+        pub fn listener(&self) -> &str {
+            &self.listener
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+            self.conn
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+    }
+
+    impl CheckpointStore for SqliteCheckpointStore {
+        fn load(&self) -> Result<Option<Checkpoint>, EoServerError> {
+            let context = || format!("failed to load checkpoint of {}", self.listener);
+            let bytes: Option<Vec<u8>> = self
+                .lock()
+                .query_row(
+                    "SELECT checkpoint FROM eo_checkpoints WHERE listener = ?1",
+                    [&self.listener],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|e| EoServerError::checkpoint(context(), e))?;
+
+            bytes
+                .map(|bytes| Checkpoint::from_bytes(&bytes))
+                .transpose()
+                .map_err(|e| EoServerError::checkpoint(context(), e))
+        }
+
+        fn save(&self, checkpoint: &Checkpoint) -> Result<(), EoServerError> {
+            let context = || format!("failed to save checkpoint of {}", self.listener);
+            let bytes = checkpoint
+                .to_bytes()
+                .map_err(|e| EoServerError::checkpoint(context(), e))?;
+            self.lock()
+                .execute(
+                    "INSERT INTO eo_checkpoints (listener, checkpoint, updated_at)
+                 VALUES (?1, ?2, strftime('%s', 'now'))
+                 ON CONFLICT (listener) DO UPDATE
+                 SET checkpoint = excluded.checkpoint, updated_at = excluded.updated_at",
+                    rusqlite::params![&self.listener, bytes],
+                )
+                .map_err(|e| EoServerError::checkpoint(context(), e))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::{checkpoint, temp_path};
+
+    /// Nothing is loaded before the first save, then the last save is
+    fn assert_round_trips(store: &dyn CheckpointStore) {
+        assert_eq!(store.load().unwrap(), None);
+        store.save(&checkpoint(10)).unwrap();
+        assert_eq!(store.load().unwrap(), Some(checkpoint(10)));
+        store.save(&checkpoint(20)).unwrap();
+        assert_eq!(store.load().unwrap(), Some(checkpoint(20)));
+    }
+
+    #[test]
+    fn memory_store_round_trips() {
+        let store = MemoryCheckpointStore::new();
+        assert_round_trips(&store);
+
+        // Clones share the checkpoint
+        let clone = store.clone();
+        clone.save(&checkpoint(30)).unwrap();
+        assert_eq!(store.get(), Some(checkpoint(30)));
+    }
+
+    #[test]
+    fn file_store_round_trips() {
+        let path = temp_path("file.dat");
+        assert_round_trips(&FileCheckpointStore::new(&path));
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::checkpoint::previous_path(&path));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trips() {
+        assert_round_trips(&SqliteCheckpointStore::open_in_memory("listener").unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_is_shared_by_concurrent_listeners() {
+        let path = temp_path("shared.db");
+        let listeners: Vec<SqliteCheckpointStore> = (0..4)
+            .map(|i| SqliteCheckpointStore::open(&path, format!("listener-{}", i)).unwrap())
+            .collect();
+
+        // Every store has its own connection, so these writes contend for
+        // the database lock
+        std::thread::scope(|scope| {
+            for (i, store) in listeners.iter().enumerate() {
+                scope.spawn(move || {
+                    for last in 0..25 {
+                        store.save(&checkpoint(i as u64 * 100 + last)).unwrap();
+                    }
+                });
+            }
+        });
+
+        for (i, store) in listeners.iter().enumerate() {
+            assert_eq!(store.load().unwrap(), Some(checkpoint(i as u64 * 100 + 24)));
+        }
+
+        drop(listeners);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
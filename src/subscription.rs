@@ -0,0 +1,793 @@
+use futures::{future::BoxFuture, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+use web3::{
+    transports::{Ipc, WebSocket},
+    types::{BlockNumber, Filter, FilterBuilder, Log, H256, U256, U64},
+    DuplexTransport, Web3,
+};
+
+use crate::{
+    is_provider_limit_error, shutdown::Shutdown, Confirmation, EoEvent, EoServer, EoServerError,
+    EoServerHandle, StopToken, DEFAULT_EVENT_BUFFER,
+};
+
+/// A duplex transport that can open a fresh connection to its endpoint.
+///
+/// `web3` transports do not reconnect on their own, so once a WebSocket or
+/// IPC connection drops every later call on it fails. Subscription mode uses
+/// this to replace the transport before resubscribing.
+pub trait Reconnect: DuplexTransport + Sized {
+    fn reconnect(endpoint: &str) -> BoxFuture<'static, web3::Result<Self>>;
+}
+
+impl Reconnect for WebSocket {
+    fn reconnect(endpoint: &str) -> BoxFuture<'static, web3::Result<Self>> {
+        let endpoint = endpoint.to_string();
+        Box::pin(async move { WebSocket::new(&endpoint).await })
+    }
+}
+
+impl Reconnect for Ipc {
+    fn reconnect(endpoint: &str) -> BoxFuture<'static, web3::Result<Self>> {
+        let endpoint = endpoint.to_string();
+        Box::pin(async move { Ipc::new(endpoint).await })
+    }
+}
+
+impl<T> EoServer<T>
+where
+    T: Reconnect,
+    T::NotificationStream: Unpin,
+{
+    /// Listen for events with an `eth_subscribe("logs")` subscription instead
+    /// of polling `eth_getLogs` once per block.
+    ///
+    /// Every time the subscription is (re)established the range since the
+    /// last log we saw is backfilled with `eth_getLogs`, so logs emitted while
+    /// we were disconnected are not lost. A configured `BackfillConfig`
+    /// catches up on history before the first subscription.
+    ///
+    /// Events are sent to `events` in chain order until a `StopToken` arrives
+    /// on `stop` or the receiver is dropped. The checkpoint is saved before
+    /// returning.
+    pub async fn run_subscribed(
+        mut self,
+        events: mpsc::Sender<EoEvent>,
+        stop: oneshot::Receiver<StopToken>,
+    ) -> Result<(), EoServerError> {
+        let mut shutdown = Shutdown::new(stop);
+        // Backfill before subscribing, the catch up after subscribing then
+        // only has to cover the blocks mined in the meantime
+        if !self.backfill(&events, &mut shutdown).await? {
+            self.subscription_loop(&events, &mut shutdown).await?;
+        }
+        self.flush_checkpoint(&events).await
+    }
+
+    async fn subscription_loop(
+        &mut self,
+        events: &mpsc::Sender<EoEvent>,
+        shutdown: &mut Shutdown,
+    ) -> Result<(), EoServerError> {
+        let filter = self.subscription_filter()?;
+
+        while !shutdown.is_stopped() {
+            if self.wait_for_circuit(shutdown).await {
+                break;
+            }
+
+            let mut subscription = match self
+                .web3
+                .eth_subscribe()
+                .subscribe_logs(filter.clone())
+                .await
+            {
+                Ok(subscription) => subscription,
+                Err(err) => {
+                    log::error!("failed to subscribe to logs: {}", err);
+                    if !shutdown.sleep(self.poll_interval()).await {
+                        self.reconnect().await;
+                    }
+                    continue;
+                }
+            };
+
+            // Subscribe before catching up so that anything emitted while the
+            // backfill is running is buffered by the subscription.
+            match self.catch_up(events).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::info!("event receiver dropped, stopping");
+                    let _ = subscription.unsubscribe().await;
+                    return Ok(());
+                }
+                // Retrying cannot fix the error, or a batch that was scanned
+                // but not delivered because its checkpoint could not be
+                // saved is only scanned again after a restart
+                Err(err)
+                    if !err.is_transient() || matches!(err, EoServerError::Checkpoint { .. }) =>
+                {
+                    let _ = subscription.unsubscribe().await;
+                    return Err(err);
+                }
+                // Going live now would move the cursor past the logs we
+                // missed, so resubscribe and catch up again instead
+                Err(err) => {
+                    log::error!("failed to catch up on missed logs: {}", err);
+                    let _ = subscription.unsubscribe().await;
+                    shutdown.sleep(self.poll_interval()).await;
+                    continue;
+                }
+            }
+
+            // Every block time release held logs that are now confirmed and
+            // record the confirmed range as scanned. One timer across batches,
+            // so a busy subscription cannot keep pushing the release back.
+            let period = self.poll_interval();
+            let mut release = tokio::time::interval_at(Instant::now() + period, period);
+            release.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                let batch = tokio::select! {
+                    // Only checked between batches, so a log that has been
+                    // taken off the subscription is always delivered
+                    _ = shutdown.wait() => {
+                        log::info!("stop requested, stopping");
+                        let _ = subscription.unsubscribe().await;
+                        return Ok(());
+                    }
+                    log = subscription.next() => match log {
+                        Some(Ok(log)) => match self.process_subscribed_log(log) {
+                            Ok(batch) => batch,
+                            Err(err) => {
+                                let _ = subscription.unsubscribe().await;
+                                return Err(err);
+                            }
+                        },
+                        Some(Err(err)) => {
+                            log::warn!("log subscription failed: {}", err);
+                            break;
+                        }
+                        None => break,
+                    },
+                    _ = release.tick() => {
+                        // Wake up again once the breaker lets calls through,
+                        // a quiet subscription would not
+                        if let Some(pause) = self.circuit_breaker.open_for() {
+                            release.reset_after(pause);
+                            continue;
+                        }
+
+                        let released = match self.head().await {
+                            Ok(head) => self.release_confirmed_logs(head).await,
+                            Err(err) => Err(err),
+                        };
+                        match released {
+                            Ok(released) => released,
+                            Err(err) if !err.is_transient() => {
+                                let _ = subscription.unsubscribe().await;
+                                return Err(err);
+                            }
+                            Err(err) => {
+                                log::warn!("failed to release confirmed logs: {}", err);
+                                Vec::new()
+                            }
+                        }
+                    }
+                };
+
+                let dropped = match self.deliver_batch(events, batch).await {
+                    Ok(dropped) => dropped,
+                    Err(err) => {
+                        let _ = subscription.unsubscribe().await;
+                        return Err(err);
+                    }
+                };
+                if dropped {
+                    log::info!("event receiver dropped, stopping");
+                    let _ = subscription.unsubscribe().await;
+                    return Ok(());
+                }
+            }
+
+            log::warn!("log subscription closed, resubscribing");
+            let _ = subscription.unsubscribe().await;
+            if !shutdown.sleep(self.poll_interval()).await {
+                self.reconnect().await;
+            }
+        }
+
+        log::info!("stop requested, stopping");
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) {
+        let Some(endpoint) = self.endpoint.clone() else {
+            return;
+        };
+
+        match T::reconnect(&endpoint).await {
+            Ok(transport) => {
+                self.web3 = Web3::new(transport);
+                self.contract = web3::contract::Contract::new(
+                    self.web3.eth(),
+                    self.contract.address(),
+                    self.contract.abi().clone(),
+                );
+                log::info!("reconnected to {}", endpoint);
+            }
+            Err(err) => log::error!("failed to reconnect to {}: {}", endpoint, err),
+        }
+    }
+
+    /// Catch up on everything the subscription has not seen, delivering
+    /// events as it goes.
+    ///
+    /// The confirmed part of the range is scanned in chunks, exactly like
+    /// polling does, so every chunk is only marked as scanned once it has
+    /// been fetched. The unconfirmed rest up to the head is fetched into the
+    /// held logs, from there on the subscription covers every new block.
+    /// Returns `true` if the listener should stop.
+    async fn catch_up(&mut self, events: &mpsc::Sender<EoEvent>) -> Result<bool, EoServerError> {
+        // Reorgs that happened while we were disconnected never reach us as
+        // removed logs, so held logs and the cursor may point at orphaned
+        // blocks. The tail below fetches everything they covered again, and
+        // events delivered before are skipped by their ID.
+        self.held_logs.clear();
+        self.subscription_cursor = None;
+
+        // Delivered events from orphaned blocks are found by comparing
+        // block hashes
+        let removed = self.check_reorg().await?;
+        if self.deliver_batch(events, removed).await? {
+            return Ok(true);
+        }
+
+        loop {
+            let (mut batch, decode_errors) = match self.scan().await {
+                Ok(decoded) => decoded,
+                // The chunk size has shrunk, try again in smaller chunks
+                Err(EoServerError::ProviderLimit { .. }) if self.poll.behind => continue,
+                Err(err) => return Err(err),
+            };
+            batch.sort_by_key(|event| (event.block_number(), event.log_index()));
+            if self.deliver_batch(events, batch).await? {
+                return Ok(true);
+            }
+            if let Some(err) = decode_errors.into_iter().next() {
+                return Err(err);
+            }
+            if !self.poll.behind {
+                break;
+            }
+        }
+
+        let head = self.head().await?;
+        let from_block = self
+            .registry
+            .iter()
+            .map(|event| event.scheduler().next_block())
+            .min()
+            .unwrap_or_default();
+        let mut chunk_size = self.chunk_sizes().into_iter().min().unwrap_or(1);
+
+        let contract_address = self.eo_address.parse()?;
+        let mut next = from_block;
+        while next <= head {
+            let to_block = std::cmp::min(next.saturating_add(U64::from(chunk_size - 1)), head);
+            log::info!("catching up from block {} to block {}", next, to_block);
+            let logs = match self.fetch_logs(contract_address, next, to_block).await {
+                Ok(logs) => logs,
+                Err(err) if is_provider_limit_error(&err) => {
+                    // Retry the chunk in smaller chunks, or back off and
+                    // resubscribe once they cannot shrink any further
+                    if let Some(smaller) = self.shrink_chunks() {
+                        log::warn!(
+                            "provider rejected blocks {} to {}, retrying in chunks of {} blocks",
+                            next,
+                            to_block,
+                            smaller
+                        );
+                        chunk_size = smaller;
+                        continue;
+                    }
+                    return Err(EoServerError::ProviderLimit {
+                        from_block: next,
+                        to_block,
+                        source: std::sync::Arc::new(err),
+                    });
+                }
+                Err(err) => {
+                    return Err(EoServerError::rpc(
+                        format!("failed to fetch logs for blocks {} to {}", next, to_block),
+                        err,
+                    ))
+                }
+            };
+            let mut batch = Vec::new();
+            for log in logs {
+                // Starting at the lowest next block refetches blocks that
+                // other events already scanned above, possibly further back
+                // than the dedup set reaches
+                let scanned = match (self.registry.position(&log), log.block_number) {
+                    (Some(index), Some(block)) => self
+                        .registry
+                        .at(index)
+                        .scheduler()
+                        .completed()
+                        .contains(block),
+                    _ => false,
+                };
+                if !scanned {
+                    batch.extend(self.process_subscribed_log(log)?);
+                }
+            }
+            if self.deliver_batch(events, batch).await? {
+                return Ok(true);
+            }
+            next = to_block + 1;
+        }
+
+        // Everything up to the head has been fetched, the same logs buffered
+        // by the subscription are skipped
+        if self
+            .subscription_cursor
+            .is_none_or(|(block, _)| block < head)
+        {
+            self.subscription_cursor = Some((head, U256::MAX));
+        }
+
+        let released = self.release_confirmed_logs(head).await?;
+        self.deliver_batch(events, released).await
+    }
+
+    /// Deliver held logs whose block has reached the confirmation depth
+    /// configured for their event.
+    ///
+    /// Only called once a catch-up has fetched everything up to the head,
+    /// after which the subscription sees every new block, so the confirmed
+    /// blocks have been covered and are marked as scanned.
+    async fn release_confirmed_logs(&mut self, head: U64) -> Result<Vec<EoEvent>, EoServerError> {
+        let confirmed = self.confirmed_blocks(head).await?;
+
+        let mut released = Vec::new();
+        let held = std::mem::take(&mut self.held_logs);
+        for ((block_number, log_index), log) in held {
+            let Some(index) = self.registry.position(&log) else {
+                continue;
+            };
+
+            if block_number <= confirmed[index] {
+                released.extend(self.deliver_subscribed_log(log)?);
+            } else {
+                self.held_logs.insert((block_number, log_index), log);
+            }
+        }
+
+        // Every log up to the confirmed blocks has been delivered, so the
+        // subscription has covered those ranges
+        for (event, confirmed) in self.registry.iter_mut().zip(confirmed) {
+            event.scheduler_mut().scanned_through(confirmed);
+        }
+
+        Ok(released)
+    }
+
+    /// Fails if the log cannot be decoded, retrying would fail the same way
+    fn process_subscribed_log(&mut self, log: Log) -> Result<Vec<EoEvent>, EoServerError> {
+        let (Some(block_number), Some(log_index)) = (log.block_number, log.log_index) else {
+            log::warn!("ignoring pending log: tx_hash = {:?}", log.transaction_hash);
+            return Ok(Vec::new());
+        };
+
+        if log.removed == Some(true) {
+            // The first removed log of a reorg retracts everything we
+            // delivered from its block onwards and rewinds the cursor so the
+            // replacement logs are processed, later ones find nothing left.
+            // Held logs from the orphaned blocks are simply dropped.
+            self.held_logs.split_off(&(block_number, U256::zero()));
+            return Ok(self.rewind_to(block_number.saturating_sub(U64::from(1))));
+        }
+
+        // Logs at or behind the cursor were already handled, either by the
+        // subscription or by a catch-up that overlapped it.
+        let position = (block_number, log_index);
+        if matches!(self.subscription_cursor, Some(cursor) if position <= cursor) {
+            return Ok(Vec::new());
+        }
+        self.subscription_cursor = Some(position);
+
+        let Some(index) = self.registry.position(&log) else {
+            log::warn!("ignoring log with unknown topic: {:?}", log.topics.first());
+            return Ok(Vec::new());
+        };
+
+        if self.registry.at(index).confirmation() != Confirmation::Blocks(0) {
+            self.held_logs.insert(position, log);
+            return Ok(Vec::new());
+        }
+
+        self.deliver_subscribed_log(log)
+    }
+
+    fn deliver_subscribed_log(&mut self, log: Log) -> Result<Vec<EoEvent>, EoServerError> {
+        let Some(index) = self.registry.position(&log) else {
+            return Ok(Vec::new());
+        };
+
+        let (events, errors) = self.handle_logs(index, vec![log]);
+        if let Some(err) = errors.into_iter().next() {
+            return Err(err);
+        }
+
+        if !events.is_empty() {
+            log::info!("discovered logs: logs.len() = {}", events.len());
+        }
+
+        Ok(events)
+    }
+
+    /// A subscription takes a single filter, so the indexed filters are only
+    /// sent to the node when every event shares them. Otherwise the node
+    /// sends every log of the registered events and the ones filtered out
+    /// are dropped when they arrive.
+    fn subscription_filter(&self) -> Result<Filter, EoServerError> {
+        let contract_address = self.eo_address.parse()?;
+        let mut groups = self.registry.log_topics();
+        let [topic0, topic1, topic2, topic3] = match groups.len() {
+            1 => groups.remove(0),
+            _ => [Some(self.registry.topics()), None, None, None],
+        };
+
+        Ok(FilterBuilder::default()
+            .address(vec![contract_address])
+            .topics(topic0, topic1, topic2, topic3)
+            .build())
+    }
+}
+
+impl<T> EoServer<T>
+where
+    T: Reconnect + Send + Sync + 'static,
+    T::Out: Send,
+    T::NotificationStream: Unpin + Send,
+{
+    /// Run the subscription listener on its own task, see `run_subscribed`.
+    /// Events are buffered up to `DEFAULT_EVENT_BUFFER`, after that the
+    /// subscription is not read until the consumer receives more.
+    pub fn spawn_subscribed(self) -> (EoServerHandle, mpsc::Receiver<EoEvent>) {
+        self.spawn_subscribed_with_buffer(DEFAULT_EVENT_BUFFER)
+    }
+
+    pub fn spawn_subscribed_with_buffer(
+        self,
+        buffer: usize,
+    ) -> (EoServerHandle, mpsc::Receiver<EoEvent>) {
+        let (events, receiver) = mpsc::channel(buffer);
+        let (stop, stop_receiver) = oneshot::channel();
+        let acks = self.acknowledger();
+        let task = tokio::spawn(self.run_subscribed(events, stop_receiver));
+        (EoServerHandle::new(task, stop, acks), receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use web3::ethabi::{encode, Token};
+    use web3::types::{Address, Bytes};
+
+    use super::*;
+    use crate::mock::{rpc_error, server_builder, MockTransport};
+    use crate::{
+        get_abi, BridgeEvent, EventRegistry, RangeScheduler, RegisteredEvent, RetryPolicy,
+    };
+
+    /// The chain as the node sees it
+    struct Chain {
+        head: u64,
+        logs: Vec<Log>,
+        /// The most blocks the node returns logs for in one call
+        max_range: u64,
+        /// How many of the next `eth_getLogs` calls fail
+        failing_calls: usize,
+    }
+
+    fn chain(head: u64, logs: Vec<Log>) -> Arc<Mutex<Chain>> {
+        Arc::new(Mutex::new(Chain {
+            head,
+            logs,
+            max_range: u64::MAX,
+            failing_calls: 0,
+        }))
+    }
+
+    /// A node serving the head and the logs of `chain`. It knows no blocks,
+    /// so reorgs only show up as removed logs or changed logs.
+    fn node(chain: Arc<Mutex<Chain>>) -> MockTransport {
+        MockTransport::new(move |method, params| {
+            let mut chain = chain.lock().unwrap();
+            match method {
+                "eth_blockNumber" => Ok(json!(format!("{:#x}", chain.head))),
+                "eth_getLogs" if chain.failing_calls > 0 => {
+                    chain.failing_calls -= 1;
+                    Err(web3::Error::Unreachable)
+                }
+                "eth_getLogs" => {
+                    let block = |key: &str| {
+                        let hex = params[0][key].as_str().unwrap().trim_start_matches("0x");
+                        u64::from_str_radix(hex, 16).unwrap()
+                    };
+                    let range = block("fromBlock")..=block("toBlock");
+                    if range.end() - range.start() >= chain.max_range {
+                        return Err(rpc_error(-32005, "query returned more than 10000 results"));
+                    }
+                    let logs: Vec<&Log> = chain
+                        .logs
+                        .iter()
+                        .filter(|log| range.contains(&log.block_number.unwrap().as_u64()))
+                        .collect();
+                    Ok(serde_json::to_value(logs).unwrap())
+                }
+                "eth_getBlockByNumber" => Ok(Value::Null),
+                _ => Err(web3::Error::Unreachable),
+            }
+        })
+    }
+
+    /// A Bridge log in block `block` of fork `fork`
+    fn bridge_log(block: u64, log_index: u64, fork: u64) -> Log {
+        let abi = get_abi().unwrap();
+        let event = abi.event(BridgeEvent::NAME).unwrap();
+        let hash = H256::from_low_u64_be(fork * 1_000 + block);
+        Log {
+            address: Address::zero(),
+            topics: vec![event.signature(), H256::zero(), H256::zero()],
+            data: Bytes(encode(&[
+                Token::Uint(U256::from(1)),
+                Token::Uint(U256::from(2)),
+                Token::String("erc20".to_string()),
+                Token::Uint(U256::from(log_index)),
+            ])),
+            block_hash: Some(hash),
+            block_number: Some(U64::from(block)),
+            transaction_hash: Some(hash),
+            transaction_index: Some(U64::zero()),
+            log_index: Some(U256::from(log_index)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        }
+    }
+
+    /// A server listening for Bridge events at `confirmation`, that neither
+    /// retries nor caches the head for long
+    fn server(chain: &Arc<Mutex<Chain>>, confirmation: Confirmation) -> EoServer<MockTransport> {
+        with_scheduler(node(chain.clone()), confirmation, RangeScheduler::default())
+    }
+
+    fn with_scheduler(
+        node: MockTransport,
+        confirmation: Confirmation,
+        scheduler: RangeScheduler,
+    ) -> EoServer<MockTransport> {
+        let event = RegisteredEvent::from_abi(&get_abi().unwrap(), BridgeEvent::NAME)
+            .unwrap()
+            .with_confirmation(confirmation)
+            .with_scheduler(scheduler);
+        let mut registry = EventRegistry::new();
+        registry.register(event).unwrap();
+
+        server_builder(node)
+            .registry(registry)
+            .retry_policy(RetryPolicy::none())
+            .block_time(Duration::from_millis(50))
+            .build()
+            .unwrap()
+    }
+
+    /// Fetch the head again on the next call
+    fn forget_head(server: &mut EoServer<MockTransport>) {
+        server.poll = Default::default();
+    }
+
+    fn received(receiver: &mut mpsc::Receiver<EoEvent>) -> Vec<EoEvent> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn forgets_held_logs_of_blocks_reorged_while_disconnected() {
+        let chain = chain(100, vec![bridge_log(98, 0, 0)]);
+        let mut server = server(&chain, Confirmation::Blocks(5));
+        let (events, mut receiver) = mpsc::channel(16);
+
+        assert!(!server.catch_up(&events).await.unwrap());
+        assert_eq!(server.held_logs.len(), 1);
+
+        // While resubscribing, block 98 is reorged out and the log is
+        // included in block 99 instead
+        {
+            let mut chain = chain.lock().unwrap();
+            chain.head = 101;
+            chain.logs = vec![bridge_log(99, 0, 1)];
+        }
+        forget_head(&mut server);
+        assert!(!server.catch_up(&events).await.unwrap());
+        assert!(received(&mut receiver).is_empty());
+
+        let released = server.release_confirmed_logs(U64::from(110)).await.unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].block_number(), U64::from(99));
+        assert_eq!(released[0].block_hash(), H256::from_low_u64_be(1_099));
+        assert!(server.held_logs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn catches_up_on_the_tail_in_smaller_chunks() {
+        let chain = chain(100, vec![bridge_log(98, 0, 0)]);
+        chain.lock().unwrap().max_range = 2;
+        let scheduler = RangeScheduler::default().with_chunk_sizes(100, 1, 100);
+        let mut server = with_scheduler(node(chain.clone()), Confirmation::Blocks(5), scheduler);
+        server
+            .registry
+            .at_mut(0)
+            .scheduler_mut()
+            .complete(U64::zero(), U64::from(95));
+        let (events, _receiver) = mpsc::channel(16);
+
+        assert!(!server.catch_up(&events).await.unwrap());
+        assert_eq!(server.chunk_sizes(), vec![1]);
+        assert_eq!(server.held_logs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backs_off_when_the_tail_cannot_shrink() {
+        let chain = chain(100, Vec::new());
+        chain.lock().unwrap().max_range = 2;
+        let scheduler = RangeScheduler::default().with_chunk_sizes(100, 4, 100);
+        let mut server = with_scheduler(node(chain.clone()), Confirmation::Blocks(5), scheduler);
+        server
+            .registry
+            .at_mut(0)
+            .scheduler_mut()
+            .complete(U64::zero(), U64::from(95));
+        let (events, _receiver) = mpsc::channel(16);
+
+        let err = server.catch_up(&events).await.unwrap_err();
+        assert!(matches!(err, EoServerError::ProviderLimit { .. }));
+        assert!(err.is_transient());
+        assert_eq!(server.chunk_sizes(), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn skips_logs_the_catch_up_has_handled() {
+        let chain = chain(100, vec![bridge_log(100, 0, 0)]);
+        let mut server = server(&chain, Confirmation::Blocks(0));
+        let (events, mut receiver) = mpsc::channel(16);
+
+        assert!(!server.catch_up(&events).await.unwrap());
+        assert_eq!(received(&mut receiver).len(), 1);
+        assert_eq!(
+            server.subscription_cursor,
+            Some((U64::from(100), U256::MAX))
+        );
+
+        // The subscription buffered the same log while the catch-up ran
+        let delivered = server
+            .process_subscribed_log(bridge_log(100, 0, 0))
+            .unwrap();
+        assert!(delivered.is_empty());
+
+        let delivered = server
+            .process_subscribed_log(bridge_log(101, 0, 0))
+            .unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(
+            server.subscription_cursor,
+            Some((U64::from(101), U256::zero()))
+        );
+    }
+
+    #[tokio::test]
+    async fn releases_held_logs_at_their_confirmation_depth() {
+        let chain = chain(100, Vec::new());
+        let mut server = server(&chain, Confirmation::Blocks(5));
+        let (events, _receiver) = mpsc::channel(16);
+        assert!(!server.catch_up(&events).await.unwrap());
+
+        for block in [101, 102] {
+            let held = server
+                .process_subscribed_log(bridge_log(block, 0, 0))
+                .unwrap();
+            assert!(held.is_empty());
+        }
+
+        let released = server.release_confirmed_logs(U64::from(105)).await.unwrap();
+        assert!(released.is_empty());
+
+        let released = server.release_confirmed_logs(U64::from(106)).await.unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].block_number(), U64::from(101));
+        assert_eq!(server.held_logs.len(), 1);
+        assert_eq!(
+            server.registry.at(0).scheduler().next_block(),
+            U64::from(102)
+        );
+    }
+
+    #[tokio::test]
+    async fn retracts_the_events_of_removed_logs() {
+        let chain = chain(100, Vec::new());
+        let mut server = server(&chain, Confirmation::Blocks(0));
+        let (events, _receiver) = mpsc::channel(16);
+        assert!(!server.catch_up(&events).await.unwrap());
+
+        for block in [101, 102] {
+            let delivered = server
+                .process_subscribed_log(bridge_log(block, 0, 0))
+                .unwrap();
+            assert_eq!(delivered.len(), 1);
+        }
+
+        let mut removed = bridge_log(101, 0, 0);
+        removed.removed = Some(true);
+        let retracted = server.process_subscribed_log(removed).unwrap();
+        let blocks: Vec<U64> = retracted.iter().map(EoEvent::block_number).collect();
+        assert_eq!(blocks, vec![U64::from(102), U64::from(101)]);
+        assert!(retracted
+            .iter()
+            .all(|event| matches!(event, EoEvent::Removed(_))));
+        assert_eq!(
+            server.subscription_cursor,
+            Some((U64::from(100), U256::MAX))
+        );
+
+        // The replacement log is not mistaken for one already handled
+        let delivered = server
+            .process_subscribed_log(bridge_log(101, 0, 1))
+            .unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].block_hash(), H256::from_low_u64_be(1_101));
+    }
+
+    #[tokio::test]
+    async fn resubscribes_when_the_catch_up_fails() {
+        let chain = chain(100, Vec::new());
+        chain.lock().unwrap().failing_calls = 1;
+        let transport = node(chain.clone());
+        let server = with_scheduler(
+            transport.clone(),
+            Confirmation::Blocks(0),
+            RangeScheduler::default(),
+        );
+        let (mut handle, mut receiver) = server.spawn_subscribed();
+
+        // Rather than going live, the listener drops the subscription and
+        // catches up again on a new one
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.calls("eth_getLogs") < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(transport.calls("eth_subscribe"), 2);
+        assert_eq!(transport.calls("eth_unsubscribe"), 1);
+
+        transport.notify(serde_json::to_value(bridge_log(101, 0, 0)).unwrap());
+        let event = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.block_number(), U64::from(101));
+        handle.acknowledger().ack();
+
+        handle.stop();
+        while receiver.recv().await.is_some() {}
+        handle.join().await.unwrap();
+    }
+}